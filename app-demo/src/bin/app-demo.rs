@@ -2,12 +2,13 @@ use std::{
     error::Error as StdError,
     fs,
     net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    process,
     time::Duration,
 };
 
 use axum::Router;
 use axum_server::{self, tls_rustls::RustlsConfig};
-use clap::{Arg as ClapArg, Command};
+use clap::{Arg as ClapArg, ArgMatches, Command};
 use log::{self, error, info};
 use serde::Deserialize;
 use sylvia_iot_sdk::util::{
@@ -31,25 +32,45 @@ const PROJ_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PROJ_VER: &'static str = env!("CARGO_PKG_VERSION");
 const HTTP_PORT: u16 = 7080;
 const HTTPS_PORT: u16 = 7443;
+const CMD_PRINT_CONFIG: &'static str = "print-config";
+const CMD_EXAMPLE_CONFIG: &'static str = "example-config";
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     const FN_NAME: &'static str = "main";
 
-    let conf = match init_config() {
+    let args = new_command().get_matches();
+    if args.subcommand_name() == Some(CMD_EXAMPLE_CONFIG) {
+        print!("{}", example_config());
+        return Ok(());
+    }
+
+    let conf = match init_config(&args) {
         Err(e) => {
             let conf = &logger::Config {
                 ..Default::default()
             };
             logger::init(PROJ_NAME, &conf);
             error!("[{}] read config error: {}", FN_NAME, e);
-            return Ok(());
+            process::exit(1);
         }
         Ok(conf) => conf,
     };
 
+    if args.subcommand_name() == Some(CMD_PRINT_CONFIG) {
+        print_config(&conf);
+        return Ok(());
+    }
+
     logger::init(PROJ_NAME, &conf.log);
 
+    if let Err(errs) = libs::config::validate(&libs::config::apply_default(&conf.app_demo)) {
+        for e in errs {
+            error!("[{}] invalid config: {}", FN_NAME, e);
+        }
+        process::exit(1);
+    }
+
     let state = match routes::new_state("/app-demo", &conf.app_demo).await {
         Err(e) => {
             error!("[{}] new routes state error: {}", FN_NAME, e);
@@ -135,27 +156,89 @@ async fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-fn init_config() -> Result<AppConfig, Box<dyn StdError>> {
-    let mut args = Command::new(PROJ_NAME).version(PROJ_VER).arg(
-        ClapArg::new("file")
-            .short('f')
-            .long("file")
-            .help("config file")
-            .num_args(1),
-    );
+/// To create the command line parser with arguments and subcommands.
+fn new_command() -> Command {
+    let mut args = Command::new(PROJ_NAME)
+        .version(PROJ_VER)
+        .arg(
+            ClapArg::new("file")
+                .short('f')
+                .long("file")
+                .help("config file")
+                .num_args(1),
+        )
+        .subcommand(
+            Command::new(CMD_PRINT_CONFIG)
+                .about("Print the resolved configuration with secrets hidden"),
+        )
+        .subcommand(
+            Command::new(CMD_EXAMPLE_CONFIG)
+                .about("Print the configuration template with default values"),
+        );
     args = logger::reg_args(args);
     args = server_config::reg_args(args);
-    args = libs::config::reg_args(args);
-    let args = args.get_matches();
+    libs::config::reg_args(args)
+}
 
+fn init_config(args: &ArgMatches) -> Result<AppConfig, Box<dyn StdError>> {
     if let Some(v) = args.get_one::<String>("file") {
         let conf_str = fs::read_to_string(v)?;
         return Ok(json5::from_str(conf_str.as_str())?);
     }
 
     Ok(AppConfig {
-        log: logger::read_args(&args),
-        server: server_config::read_args(&args),
-        app_demo: libs::config::read_args(&args),
+        log: logger::read_args(args),
+        server: server_config::read_args(args),
+        app_demo: libs::config::read_args(args),
     })
 }
+
+/// To print the resolved configuration in JSON5 (JSON) format.
+fn print_config(conf: &AppConfig) {
+    let app_demo = libs::config::redact(&libs::config::apply_default(&conf.app_demo));
+    let value = serde_json::json!({
+        "log": {
+            "level": conf.log.level,
+            "style": conf.log.style,
+        },
+        "server": {
+            "httpPort": conf.server.http_port,
+            "httpsPort": conf.server.https_port,
+            "cacertFile": conf.server.cacert_file,
+            "certFile": conf.server.cert_file,
+            "keyFile": conf.server.key_file,
+            "staticPath": conf.server.static_path,
+        },
+        "appDemo": app_demo,
+    });
+    match serde_json::to_string_pretty(&value) {
+        Err(e) => eprintln!("print config error: {}", e),
+        Ok(value) => println!("{}", value),
+    }
+}
+
+/// To generate the commented configuration template with default values.
+fn example_config() -> String {
+    let mut out = String::new();
+    out.push_str("{\n");
+    out.push_str("    \"log\": {\n");
+    out.push_str("        \"level\": \"info\", // off, error, warn, info, debug\n");
+    out.push_str("        \"style\": \"json\", // json, log4j\n");
+    out.push_str("    },\n");
+    out.push_str("    \"server\": {\n");
+    out.push_str(&format!("        \"httpPort\": {},\n", HTTP_PORT));
+    out.push_str(&format!("        //\"httpsPort\": {},\n", HTTPS_PORT));
+    out.push_str("        //\"cacertFile\": null, // HTTPS CA certificate file\n");
+    out.push_str("        //\"certFile\": null,   // HTTPS certificate file\n");
+    out.push_str("        //\"keyFile\": null,    // HTTPS private key file\n");
+    out.push_str("    },\n");
+    out.push_str("    \"appDemo\": {\n");
+    for line in libs::config::example().lines() {
+        out.push_str("        ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("    },\n");
+    out.push_str("}\n");
+    out
+}
@@ -2,19 +2,20 @@ use std::{
     error::Error as StdError,
     fs,
     net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    process,
     time::Duration,
 };
 
 use axum::Router;
-use axum_server::{self, tls_rustls::RustlsConfig};
-use clap::{Arg as ClapArg, Command};
+use axum_server::{self, tls_rustls::RustlsConfig, Handle};
+use clap::{Arg as ClapArg, ArgMatches, Command};
 use log::{self, error, info};
 use serde::Deserialize;
 use sylvia_iot_sdk::util::{
     logger::{self, LoggerLayer},
     server_config,
 };
-use tokio::{self, net::TcpListener};
+use tokio::{self, net::TcpListener, signal};
 use tower_http::{cors::CorsLayer, normalize_path::NormalizePathLayer, timeout::TimeoutLayer};
 
 use lora_ifroglab::{libs, routes};
@@ -31,25 +32,46 @@ const PROJ_NAME: &'static str = env!("CARGO_PKG_NAME");
 const PROJ_VER: &'static str = env!("CARGO_PKG_VERSION");
 const HTTP_PORT: u16 = 6080;
 const HTTPS_PORT: u16 = 6443;
+const CMD_PRINT_CONFIG: &'static str = "print-config";
+const CMD_EXAMPLE_CONFIG: &'static str = "example-config";
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     const FN_NAME: &'static str = "main";
 
-    let conf = match init_config() {
+    let args = new_command().get_matches();
+    if args.subcommand_name() == Some(CMD_EXAMPLE_CONFIG) {
+        print!("{}", example_config());
+        return Ok(());
+    }
+
+    let conf = match init_config(&args) {
         Err(e) => {
             let conf = &logger::Config {
                 ..Default::default()
             };
             logger::init(PROJ_NAME, &conf);
             error!("[{}] read config error: {}", FN_NAME, e);
-            return Ok(());
+            process::exit(1);
         }
         Ok(conf) => conf,
     };
 
+    if args.subcommand_name() == Some(CMD_PRINT_CONFIG) {
+        print_config(&conf);
+        return Ok(());
+    }
+
     logger::init(PROJ_NAME, &conf.log);
 
+    if let Err(errs) = libs::config::validate(&libs::config::apply_default(&conf.lora_ifroglab)) {
+        for e in errs {
+            error!("[{}] invalid config: {}", FN_NAME, e);
+        }
+        process::exit(1);
+    }
+
     let state = match routes::new_state("/lora-ifroglab", &conf.lora_ifroglab).await {
         Err(e) => {
             error!("[{}] new routes state error: {}", FN_NAME, e);
@@ -90,15 +112,20 @@ async fn main() -> std::io::Result<()> {
                 None => SocketAddr::V6(SocketAddrV6::new(ipv6_addr, HTTPS_PORT, 0, 0)),
                 Some(port) => SocketAddr::V6(SocketAddrV6::new(ipv6_addr, port, 0, 0)),
             };
+            let handle = Handle::new();
             let http_app = app.clone();
+            let http_handle = handle.clone();
             let http_serv = tokio::spawn(async move {
                 axum_server::bind(http_addr)
+                    .handle(http_handle)
                     .serve(http_app.into_make_service_with_connect_info::<SocketAddr>())
                     .await
                     .unwrap()
             });
+            let https_handle = handle.clone();
             let https_serv = tokio::spawn(async move {
                 axum_server::bind_rustls(addr, config)
+                    .handle(https_handle)
                     .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                     .await
                     .unwrap()
@@ -107,7 +134,13 @@ async fn main() -> std::io::Result<()> {
                 "[{}] running {} service (v{})",
                 FN_NAME, PROJ_NAME, PROJ_VER
             );
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                handle.graceful_shutdown(Some(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS)));
+            });
             let _ = tokio::join!(http_serv, https_serv);
+            state.shutdown().await;
+            info!("[{}] stopped {} service", FN_NAME, PROJ_NAME);
             return Ok(());
         }
     }
@@ -127,35 +160,131 @@ async fn main() -> std::io::Result<()> {
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     {
         error!("[{}] launch server error: {}", FN_NAME, e);
-        return Ok(());
     }
+    state.shutdown().await;
+    info!("[{}] stopped {} service", FN_NAME, PROJ_NAME);
     Ok(())
 }
 
-fn init_config() -> Result<AppConfig, Box<dyn StdError>> {
-    let mut args = Command::new(PROJ_NAME).version(PROJ_VER).arg(
-        ClapArg::new("file")
-            .short('f')
-            .long("file")
-            .help("config file")
-            .num_args(1),
-    );
+/// To wait for Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    const FN_NAME: &'static str = "shutdown_signal";
+
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("[{}] listen Ctrl+C error: {}", FN_NAME, e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Err(e) => {
+                error!("[{}] listen SIGTERM error: {}", FN_NAME, e);
+                std::future::pending::<()>().await;
+            }
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("[{}] shutting down", FN_NAME);
+}
+
+/// To create the command line parser with arguments and subcommands.
+fn new_command() -> Command {
+    let mut args = Command::new(PROJ_NAME)
+        .version(PROJ_VER)
+        .arg(
+            ClapArg::new("file")
+                .short('f')
+                .long("file")
+                .help("config file")
+                .num_args(1),
+        )
+        .subcommand(
+            Command::new(CMD_PRINT_CONFIG)
+                .about("Print the resolved configuration with secrets hidden"),
+        )
+        .subcommand(
+            Command::new(CMD_EXAMPLE_CONFIG)
+                .about("Print the configuration template with default values"),
+        );
     args = logger::reg_args(args);
     args = server_config::reg_args(args);
-    args = libs::config::reg_args(args);
-    let args = args.get_matches();
+    libs::config::reg_args(args)
+}
 
+fn init_config(args: &ArgMatches) -> Result<AppConfig, Box<dyn StdError>> {
     if let Some(v) = args.get_one::<String>("file") {
         let conf_str = fs::read_to_string(v)?;
         return Ok(json5::from_str(conf_str.as_str())?);
     }
 
     Ok(AppConfig {
-        log: logger::read_args(&args),
-        server: server_config::read_args(&args),
-        lora_ifroglab: libs::config::read_args(&args),
+        log: logger::read_args(args),
+        server: server_config::read_args(args),
+        lora_ifroglab: libs::config::read_args(args)?,
     })
 }
+
+/// To print the resolved configuration in JSON5 (JSON) format.
+fn print_config(conf: &AppConfig) {
+    let lora_ifroglab = libs::config::redact(&libs::config::apply_default(&conf.lora_ifroglab));
+    let value = serde_json::json!({
+        "log": {
+            "level": conf.log.level,
+            "style": conf.log.style,
+        },
+        "server": {
+            "httpPort": conf.server.http_port,
+            "httpsPort": conf.server.https_port,
+            "cacertFile": conf.server.cacert_file,
+            "certFile": conf.server.cert_file,
+            "keyFile": conf.server.key_file,
+            "staticPath": conf.server.static_path,
+        },
+        "loraIfroglab": lora_ifroglab,
+    });
+    match serde_json::to_string_pretty(&value) {
+        Err(e) => eprintln!("print config error: {}", e),
+        Ok(value) => println!("{}", value),
+    }
+}
+
+/// To generate the commented configuration template with default values.
+fn example_config() -> String {
+    let mut out = String::new();
+    out.push_str("{\n");
+    out.push_str("    \"log\": {\n");
+    out.push_str("        \"level\": \"info\", // off, error, warn, info, debug\n");
+    out.push_str("        \"style\": \"json\", // json, log4j\n");
+    out.push_str("    },\n");
+    out.push_str("    \"server\": {\n");
+    out.push_str(&format!("        \"httpPort\": {},\n", HTTP_PORT));
+    out.push_str(&format!("        //\"httpsPort\": {},\n", HTTPS_PORT));
+    out.push_str("        //\"cacertFile\": null, // HTTPS CA certificate file\n");
+    out.push_str("        //\"certFile\": null,   // HTTPS certificate file\n");
+    out.push_str("        //\"keyFile\": null,    // HTTPS private key file\n");
+    out.push_str("    },\n");
+    out.push_str("    \"loraIfroglab\": {\n");
+    for line in libs::config::example().lines() {
+        out.push_str("        ");
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("    },\n");
+    out.push_str("}\n");
+    out
+}
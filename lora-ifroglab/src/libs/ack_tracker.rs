@@ -0,0 +1,106 @@
+//! Tracks acknowledgments of transmitted downlink data.
+//!
+//! In the `echo4` mode, the node acknowledges the downlink data by echoing the first 4 bytes of the
+//! payload (or the whole payload if it is shorter) at the beginning of its next uplink data.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::DlData;
+
+/// Acknowledgment mode: no acknowledgment tracking.
+pub const ACK_MODE_NONE: &'static str = "none";
+/// Acknowledgment mode: the node echoes the first 4 bytes of the downlink payload.
+pub const ACK_MODE_ECHO4: &'static str = "echo4";
+
+/// The number of echoed bytes in the `echo4` mode.
+const ECHO_LEN: usize = 4;
+
+/// The downlink data that is waiting for the acknowledgment.
+struct Pending {
+    data: DlData,
+    echo: Vec<u8>,
+    deadline: DateTime<Utc>,
+}
+
+/// The action for the downlink data without acknowledgment before the deadline.
+pub enum AckTimeout {
+    /// To transmit the data again. `ack_retries` of the data has been increased.
+    Retry(DlData),
+    /// No more retries and the data should be reported as undelivered.
+    Failed(DlData),
+}
+
+/// The acknowledgment state of transmitted downlink data. This does not access serial ports or the
+/// broker, so the caller reports results and re-queues data.
+pub struct AckTracker {
+    timeout: TimeDelta,
+    retries: u32,
+    pending: VecDeque<Pending>,
+}
+
+impl AckTracker {
+    /// To create the tracker with the acknowledgment timeout in seconds and the retry times.
+    pub fn new(timeout_secs: u64, retries: u32) -> Self {
+        AckTracker {
+            timeout: TimeDelta::seconds(timeout_secs as i64),
+            retries,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// To wait for the acknowledgment of the data that is transmitted at `now`. `payload` is the
+    /// plain payload before encryption and should not be empty, or any uplink data of the node
+    /// will be treated as the acknowledgment.
+    pub fn track(&mut self, data: DlData, payload: &[u8], now: DateTime<Utc>) {
+        let len = payload.len().min(ECHO_LEN);
+        self.pending.push_back(Pending {
+            data,
+            echo: payload[..len].to_vec(),
+            deadline: now + self.timeout,
+        });
+    }
+
+    /// To match the uplink data with the oldest pending data of the address.
+    ///
+    /// Returns the acknowledged data.
+    pub fn on_uplink(&mut self, addr: &str, payload: &[u8]) -> Option<DlData> {
+        let index = self.pending.iter().position(|pending| {
+            pending.data.network_addr.as_str() == addr && payload.starts_with(&pending.echo)
+        })?;
+        self.pending.remove(index).map(|pending| pending.data)
+    }
+
+    /// To remove data that pass the deadline at `now`.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<AckTimeout> {
+        let mut result = vec![];
+        let mut pending = VecDeque::with_capacity(self.pending.len());
+        for item in self.pending.drain(..) {
+            if item.deadline > now {
+                pending.push_back(item);
+                continue;
+            }
+            let mut data = item.data;
+            match data.ack_retries < self.retries {
+                false => result.push(AckTimeout::Failed(data)),
+                true => {
+                    data.ack_retries += 1;
+                    result.push(AckTimeout::Retry(data));
+                }
+            }
+        }
+        self.pending = pending;
+        result
+    }
+
+    /// The number of data waiting for acknowledgments.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns `true` if no data is waiting for acknowledgments.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
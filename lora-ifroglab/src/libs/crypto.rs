@@ -0,0 +1,167 @@
+//! Payload encryption between the gateway and nodes.
+//!
+//! Payloads are encrypted with AES-128-CTR. The encrypted payload is prefixed with a 2-byte nonce:
+//!
+//! ```text
+//! +-----------------------------------+
+//! |  Nonce  |   Encrypted payload     |
+//! +-----------------------------------+
+//!   2 bytes        0~6 bytes
+//! ```
+//!
+//! The 16-byte initial counter block is:
+//!
+//! ```text
+//! +-------------------------------------------------------+
+//! | Node Address | Direction |  Nonce  |      (Zero)      |
+//! +-------------------------------------------------------+
+//!     4 bytes       1 byte     2 bytes       9 bytes
+//! ```
+//!
+//! - Direction: 0 for uplink and 1 for downlink data.
+//! - Nonce: the uplink nonce is chosen by the node. The downlink nonce is a per-node counter of the
+//!   gateway that increases for each downlink data. Counters are kept in `nonceFile` across
+//!   restarts, and the key of the node should be changed before the counter wraps around (65536
+//!   downlink data).
+//!
+//! There is no integrity tag because of the small payload size. Decryption fails only when the
+//! payload is shorter than the nonce, and payloads encrypted with a wrong key are decrypted into
+//! meaningless data without errors.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error as IoError, ErrorKind},
+    sync::Mutex,
+};
+
+use aes::Aes128;
+use chrono::Utc;
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher},
+    Ctr128BE,
+};
+
+use super::queue_file;
+
+/// The key size of AES-128.
+pub const KEY_LEN: usize = 16;
+/// The nonce size in front of the encrypted payload.
+pub const NONCE_LEN: usize = 2;
+
+const DIR_UPLINK: u8 = 0;
+const DIR_DOWNLINK: u8 = 1;
+
+/// To parse a 16-byte key from a hexadecimal string.
+pub fn parse_key(key: &str) -> Result<[u8; KEY_LEN], String> {
+    let bytes = match hex::decode(key) {
+        Err(e) => return Err(format!("invalid hexadecimal key: {}", e)),
+        Ok(bytes) => bytes,
+    };
+    match <[u8; KEY_LEN]>::try_from(bytes.as_slice()) {
+        Err(_) => Err(format!("key should be {} bytes", KEY_LEN)),
+        Ok(key) => Ok(key),
+    }
+}
+
+/// Downlink nonce counters of nodes that are shared by all LoRa tasks. The key of the map is the
+/// network address and the value is the last used nonce.
+pub struct NonceCounters {
+    counters: Mutex<HashMap<String, u16>>,
+    /// The file to persist counters. `None` to keep counters in memory only.
+    path: Option<String>,
+    /// To keep file writes in the order of counter updates.
+    save_lock: Mutex<()>,
+}
+
+impl NonceCounters {
+    /// To create counters and load saved counters from the file. A missing file means no counters.
+    /// Unreadable or corrupted files are errors because nonces may be reused without counters.
+    pub fn new(path: Option<String>) -> Result<Self, IoError> {
+        let counters = match path.as_ref() {
+            None => HashMap::new(),
+            Some(path) => match fs::read_to_string(path.as_str()) {
+                Err(e) => match e.kind() {
+                    ErrorKind::NotFound => HashMap::new(),
+                    _ => return Err(e),
+                },
+                Ok(content) => match serde_json::from_str(content.as_str()) {
+                    Err(e) => {
+                        let e = format!("corrupted nonce file {}: {}", path, e);
+                        return Err(IoError::new(ErrorKind::InvalidData, e));
+                    }
+                    Ok(counters) => counters,
+                },
+            },
+        };
+        Ok(NonceCounters {
+            counters: Mutex::new(counters),
+            path,
+            save_lock: Mutex::new(()),
+        })
+    }
+
+    /// To get the next downlink nonce of the node. The counter starts from the current time for
+    /// nodes without saved counters and increases for each call. The 16-bit counter wraps around
+    /// after 65536 downlink data, so keys should be changed before that.
+    ///
+    /// Counters are saved into the file before the nonce is returned. The nonce must not be used
+    /// if saving fails.
+    pub fn next(&self, addr: &str) -> Result<u16, IoError> {
+        let _save_lock = self.save_lock.lock().unwrap();
+        let (nonce, content) = {
+            let mut mutex = self.counters.lock().unwrap();
+            let nonce = match (*mutex).get(addr) {
+                None => Utc::now().timestamp_millis() as u16,
+                Some(nonce) => nonce.wrapping_add(1),
+            };
+            (*mutex).insert(addr.to_string(), nonce);
+            let content = match self.path.is_some() {
+                false => None,
+                true => match serde_json::to_string(&*mutex) {
+                    Err(e) => return Err(IoError::new(ErrorKind::InvalidData, e)),
+                    Ok(content) => Some(content),
+                },
+            };
+            (nonce, content)
+        };
+        if let (Some(path), Some(content)) = (self.path.as_ref(), content) {
+            queue_file::save(path.as_str(), content.as_str())?;
+        }
+        Ok(nonce)
+    }
+}
+
+/// To decrypt the uplink payload (nonce and encrypted payload) from the node.
+pub fn decrypt_uplink(
+    key: &[u8; KEY_LEN],
+    node_id: u32,
+    payload: &[u8],
+) -> Result<Vec<u8>, String> {
+    if payload.len() < NONCE_LEN {
+        return Err(format!("payload should be at least {} bytes", NONCE_LEN));
+    }
+    let nonce = u16::from_be_bytes([payload[0], payload[1]]);
+    let mut data = payload[NONCE_LEN..].to_vec();
+    apply_keystream(key, node_id, DIR_UPLINK, nonce, data.as_mut_slice());
+    Ok(data)
+}
+
+/// To encrypt the downlink payload to the node. The result is prefixed with the nonce.
+pub fn encrypt_downlink(key: &[u8; KEY_LEN], node_id: u32, nonce: u16, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(NONCE_LEN + payload.len());
+    data.extend_from_slice(&nonce.to_be_bytes());
+    data.extend_from_slice(payload);
+    apply_keystream(key, node_id, DIR_DOWNLINK, nonce, &mut data[NONCE_LEN..]);
+    data
+}
+
+/// To encrypt or decrypt data in place.
+fn apply_keystream(key: &[u8; KEY_LEN], node_id: u32, dir: u8, nonce: u16, data: &mut [u8]) {
+    let mut iv = [0u8; 16];
+    iv[0..4].copy_from_slice(&node_id.to_be_bytes());
+    iv[4] = dir;
+    iv[5..7].copy_from_slice(&nonce.to_be_bytes());
+    let mut cipher = Ctr128BE::<Aes128>::new(key.into(), &iv.into());
+    cipher.apply_keystream(data);
+}
@@ -1,44 +1,195 @@
 //! Implements data handlers for network data from the broker.
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     sync::{Arc, Mutex},
 };
 
 use async_trait::async_trait;
-use chrono::Utc;
-use log::{error, info};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info, warn};
 use sylvia_iot_sdk::{
     mq::{
         network::{
             DlData as NetDlData, DlDataResult as NetDlDataResult, EventHandler, NetworkCtrlMsg,
-            NetworkMgr,
+            NetworkMgr, UlData as NetUlData,
         },
         MgrStatus,
     },
     util::strings,
 };
+use tokio::sync::Notify;
 
-use super::DlData;
+use super::{
+    metrics::Metrics, result_buffer::ResultBuffer, DlData, NodeStatus, BROADCAST_ADDR,
+    DL_RESULT_CANCELED, DL_RESULT_INVALID, DL_RESULT_QUEUE_FULL, DL_RESULT_RECEIVED,
+    DL_RESULT_SUPERSEDED, DL_RESULT_UNKNOWN_DEVICE,
+};
+
+pub struct Options {
+    pub queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>,
+    pub devices: Arc<Mutex<HashSet<String>>>,
+    /// The status of each address heard by LoRa tasks.
+    pub nodes: Arc<Mutex<HashMap<String, NodeStatus>>>,
+    /// Uplink data buffered by LoRa tasks while the message queue is not ready. The data will be
+    /// sent in order when the message queue is ready.
+    pub offline_uldata: Arc<Mutex<VecDeque<NetUlData>>>,
+    pub metrics: Arc<Metrics>,
+    pub max_queue_per_addr: usize,
+    /// Maximum downlink payload size in bytes.
+    pub max_dl_payload_bytes: usize,
+    /// To reject downlink data for devices that are not added by the broker.
+    pub whitelist_only: bool,
+    /// To reject downlink data for addresses that are not heard by the gateway.
+    pub reject_unknown_addr: bool,
+    /// Addresses that are not heard within this time in seconds are unknown. `0` means no limit.
+    pub unknown_addr_grace_secs: u64,
+    /// To notify LoRa tasks to send queued data immediately. Empty for sending data after RX.
+    pub tx_notify: Vec<Arc<Notify>>,
+    /// New downlink data replaces all queuing data of the address.
+    pub replace_latest: bool,
+    /// Downlink data results that cannot be sent to the broker. This is shared with LoRa tasks.
+    pub result_buffer: Arc<ResultBuffer>,
+}
 
 pub struct MgrHandler {
     queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>,
+    devices: Arc<Mutex<HashSet<String>>>,
+    nodes: Arc<Mutex<HashMap<String, NodeStatus>>>,
+    offline_uldata: Arc<Mutex<VecDeque<NetUlData>>>,
+    metrics: Arc<Metrics>,
+    max_queue_per_addr: usize,
+    max_dl_payload_bytes: usize,
+    whitelist_only: bool,
+    reject_unknown_addr: bool,
+    unknown_addr_grace_secs: u64,
+    tx_notify: Vec<Arc<Notify>>,
+    replace_latest: bool,
+    result_buffer: Arc<ResultBuffer>,
+    /// Data IDs of recently accepted downlink data and the received time to drop redeliveries
+    /// from the broker.
+    recent_ids: Mutex<VecDeque<(String, DateTime<Utc>)>>,
 }
 
+/// The maximum number of addresses of one `add-device-range`/`del-device-range` message.
+const MAX_RANGE_ADDRS: u128 = 65536;
+/// The maximum number of recently accepted data IDs.
+const RECENT_IDS_MAX: usize = 1024;
+/// Recently accepted data IDs are kept for this time in seconds.
+const RECENT_IDS_SECS: i64 = 600;
+
 impl MgrHandler {
-    pub fn new(queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>) -> Self {
-        MgrHandler { queue_dldata }
+    pub fn new(opts: Options) -> Self {
+        MgrHandler {
+            queue_dldata: opts.queue_dldata,
+            devices: opts.devices,
+            nodes: opts.nodes,
+            offline_uldata: opts.offline_uldata,
+            metrics: opts.metrics,
+            max_queue_per_addr: opts.max_queue_per_addr,
+            max_dl_payload_bytes: opts.max_dl_payload_bytes,
+            whitelist_only: opts.whitelist_only,
+            reject_unknown_addr: opts.reject_unknown_addr,
+            unknown_addr_grace_secs: opts.unknown_addr_grace_secs,
+            tx_notify: opts.tx_notify,
+            replace_latest: opts.replace_latest,
+            result_buffer: opts.result_buffer,
+            recent_ids: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// To check if the address is not added by the broker when `whitelist_only` is enabled.
+    fn is_unknown(&self, addr: &str) -> bool {
+        if !self.whitelist_only || addr == BROADCAST_ADDR {
+            return false;
+        }
+        let mutex = self.devices.lock().unwrap();
+        !(*mutex).contains(addr.to_lowercase().as_str())
+    }
+
+    /// To check if the address is not heard within the grace time when `reject_unknown_addr` is
+    /// enabled.
+    fn is_unheard(&self, addr: &str) -> bool {
+        if !self.reject_unknown_addr || addr == BROADCAST_ADDR {
+            return false;
+        }
+        let mutex = self.nodes.lock().unwrap();
+        match (*mutex).get(addr.to_lowercase().as_str()) {
+            None => true,
+            Some(node) => match self.unknown_addr_grace_secs {
+                0 => false,
+                secs => Utc::now() - node.last_seen > TimeDelta::seconds(secs as i64),
+            },
+        }
+    }
+
+    /// To send buffered uplink data in order. The remaining data will be kept if sending fails.
+    fn flush_uldata(&self, mgr: &NetworkMgr) {
+        const FN_NAME: &'static str = "MgrHandler::flush_uldata";
+
+        let mut mutex = self.offline_uldata.lock().unwrap();
+        let mut count = 0;
+        while let Some(data) = (*mutex).pop_front() {
+            if let Err(e) = mgr.send_uldata(&data) {
+                error!("[{}] send uldata message error: {}", FN_NAME, e);
+                Metrics::inc(&self.metrics.publish_errors);
+                (*mutex).push_front(data);
+                break;
+            }
+            Metrics::inc(&self.metrics.uldata_published);
+            count += 1;
+        }
+        if count > 0 {
+            info!(
+                "[{}] flush {} uplink data, remain {}",
+                FN_NAME,
+                count,
+                (*mutex).len()
+            );
+        }
     }
 }
 
 #[async_trait]
 impl EventHandler for MgrHandler {
-    async fn on_status_change(&self, _mgr: &NetworkMgr, _status: MgrStatus) {}
+    async fn on_status_change(&self, mgr: &NetworkMgr, status: MgrStatus) {
+        const FN_NAME: &'static str = "MgrHandler::on_status_change";
+
+        match status {
+            MgrStatus::NotReady => {
+                warn!("[{}] message queue not ready", FN_NAME);
+                Metrics::inc(&self.metrics.mq_disconnects);
+            }
+            MgrStatus::Ready => {
+                info!("[{}] message queue ready", FN_NAME);
+                self.flush_uldata(mgr);
+                self.result_buffer.flush(mgr, &self.metrics);
+            }
+        }
+    }
 
     async fn on_dldata(&self, mgr: &NetworkMgr, data: Box<NetDlData>) -> Result<(), ()> {
         const FN_NAME: &'static str = "MgrHandler::on_dldata";
 
-        let addr = &data.network_addr;
+        // Use lowercase addresses to match addresses of uplink data.
+        let addr = &data.network_addr.to_lowercase();
+
+        let expires_at = match data.extension.as_ref() {
+            None => None,
+            Some(ext) => match ext.get("expiresAt") {
+                None => None,
+                Some(value) => match value.as_str().map(DateTime::parse_from_rfc3339) {
+                    Some(Ok(time)) => Some(time.with_timezone(&Utc)),
+                    _ => {
+                        warn!(
+                            "[{}] ignore invalid expiresAt {} of data {}",
+                            FN_NAME, value, data.data_id
+                        );
+                        None
+                    }
+                },
+            },
+        };
 
         let push_data = DlData {
             data_id: data.data_id.clone(),
@@ -46,37 +197,270 @@ impl EventHandler for MgrHandler {
             publish: strings::time_str(&data.publish),
             sent: "".to_string(),
             data: hex::encode(&data.data),
-            network_addr: data.network_addr.clone(),
+            network_addr: addr.clone(),
+            attempts: 0,
+            latency_ms: None,
+            queued_ms: None,
+            ack_retries: 0,
+            tx_freq: None,
+            result: None,
+            result_message: None,
+            airtime_ms: 0,
+            priority: match data.extension.as_ref() {
+                None => 0,
+                Some(ext) => match ext.get("priority") {
+                    None => 0,
+                    Some(value) => value.as_i64().unwrap_or(0),
+                },
+            },
+            expires_at,
         };
 
         info!("[{}] receive data {:?}", FN_NAME, push_data);
 
-        {
+        let payload_len = data.data.len();
+        if payload_len > self.max_dl_payload_bytes {
+            warn!(
+                "[{}] reject data {} with {} bytes",
+                FN_NAME, data.data_id, payload_len
+            );
+            let result = NetDlDataResult {
+                data_id: data.data_id,
+                status: DL_RESULT_INVALID,
+                message: Some(format!("exceed {}-byte payload", self.max_dl_payload_bytes)),
+            };
+            if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
+                error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                Metrics::inc(&self.metrics.publish_errors);
+            }
+            return Ok(());
+        }
+        if self.is_unheard(addr) {
+            warn!("[{}] reject data for unheard address {}", FN_NAME, addr);
+            let result = NetDlDataResult {
+                data_id: data.data_id,
+                status: DL_RESULT_UNKNOWN_DEVICE,
+                message: Some(format!("address {} is not heard", addr)),
+            };
+            if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
+                error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                Metrics::inc(&self.metrics.publish_errors);
+            }
+            return Ok(());
+        }
+        if self.is_unknown(addr) {
+            warn!("[{}] reject data for unknown device {}", FN_NAME, addr);
+            let result = NetDlDataResult {
+                data_id: data.data_id,
+                status: DL_RESULT_UNKNOWN_DEVICE,
+                message: Some(format!("unknown device {}", addr)),
+            };
+            if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
+                error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                Metrics::inc(&self.metrics.publish_errors);
+            }
+            return Ok(());
+        }
+
+        // Replace queuing data with the `replace` extension or the `replaceLatest` queue mode.
+        let replace = self.replace_latest
+            || match data.extension.as_ref() {
+                None => false,
+                Some(ext) => match ext.get("replace") {
+                    None => false,
+                    Some(value) => value.as_bool().unwrap_or(false),
+                },
+            };
+        let queued = {
+            let mut recent_ids = self.recent_ids.lock().unwrap();
+            let now = Utc::now();
+            while let Some((_, time)) = recent_ids.front() {
+                match now - *time > TimeDelta::seconds(RECENT_IDS_SECS) {
+                    false => break,
+                    true => recent_ids.pop_front(),
+                };
+            }
             let mut mutex = self.queue_dldata.lock().unwrap();
             if !(*mutex).contains_key(addr) {
                 (*mutex).insert(addr.clone(), VecDeque::<DlData>::new());
             }
-            (*mutex).get_mut(addr).unwrap().push_back(push_data);
-        }
+            // Check the cap with the same lock of pushing to avoid racing with LoRa tasks.
+            let queue = (*mutex).get_mut(addr).unwrap();
+            if queue.iter().any(|x| x.data_id == push_data.data_id)
+                || recent_ids.iter().any(|(id, _)| *id == push_data.data_id)
+            {
+                Err(None)
+            } else if !replace && queue.len() >= self.max_queue_per_addr {
+                Err(Some(queue.len()))
+            } else {
+                recent_ids.push_back((push_data.data_id.clone(), now));
+                if recent_ids.len() > RECENT_IDS_MAX {
+                    recent_ids.pop_front();
+                }
+                let superseded: Vec<DlData> = match replace {
+                    false => vec![],
+                    true => queue.drain(..).collect(),
+                };
+                // Insert ahead of data with lower priority. Keep FIFO for the same priority.
+                match queue.iter().position(|x| x.priority < push_data.priority) {
+                    None => queue.push_back(push_data),
+                    Some(index) => queue.insert(index, push_data),
+                }
+                Ok(superseded)
+            }
+        };
 
-        let result = NetDlDataResult {
-            data_id: data.data_id,
-            status: -1,
-            message: None,
+        let result = match queued {
+            Err(None) => {
+                // Still respond the received result to stop redeliveries of the broker.
+                warn!("[{}] skip duplicated data {}", FN_NAME, data.data_id);
+                Metrics::inc(&self.metrics.dldata_duplicated);
+                NetDlDataResult {
+                    data_id: data.data_id,
+                    status: DL_RESULT_RECEIVED,
+                    message: None,
+                }
+            }
+            Err(Some(depth)) => {
+                warn!(
+                    "[{}] reject data {} for {} with queue depth {}",
+                    FN_NAME, data.data_id, addr, depth
+                );
+                NetDlDataResult {
+                    data_id: data.data_id,
+                    status: DL_RESULT_QUEUE_FULL,
+                    message: Some(format!("queue full with {} data", self.max_queue_per_addr)),
+                }
+            }
+            Ok(superseded) => {
+                for old in superseded {
+                    info!(
+                        "[{}] data {} superseded by {}",
+                        FN_NAME, old.data_id, data.data_id
+                    );
+                    Metrics::inc(&self.metrics.dldata_superseded);
+                    let result = NetDlDataResult {
+                        data_id: old.data_id,
+                        status: DL_RESULT_SUPERSEDED,
+                        message: Some(format!("superseded by {}", data.data_id)),
+                    };
+                    if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
+                        error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                        Metrics::inc(&self.metrics.publish_errors);
+                    }
+                }
+                Metrics::inc(&self.metrics.dldata_queued);
+                for notify in self.tx_notify.iter() {
+                    notify.notify_one();
+                }
+                NetDlDataResult {
+                    data_id: data.data_id,
+                    status: DL_RESULT_RECEIVED,
+                    message: None,
+                }
+            }
         };
 
-        if let Err(e) = mgr.send_dldata_result(&result) {
+        if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
             error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+            Metrics::inc(&self.metrics.publish_errors);
         }
 
         Ok(())
     }
 
-    async fn on_ctrl(&self, _mgr: &NetworkMgr, _data: Box<NetworkCtrlMsg>) -> Result<(), ()> {
+    async fn on_ctrl(&self, mgr: &NetworkMgr, data: Box<NetworkCtrlMsg>) -> Result<(), ()> {
         const FN_NAME: &'static str = "MgrHandler::on_ctrl";
 
-        info!("[{}] receive data", FN_NAME);
+        let (add, addrs) = match *data {
+            NetworkCtrlMsg::AddDevice { new, .. } => (true, Ok(vec![new.network_addr])),
+            NetworkCtrlMsg::AddDeviceBulk { new, .. } => (true, Ok(new.network_addrs)),
+            NetworkCtrlMsg::AddDeviceRange { new, .. } => {
+                (true, range_addrs(&new.start_addr, &new.end_addr))
+            }
+            NetworkCtrlMsg::DelDevice { new, .. } => (false, Ok(vec![new.network_addr])),
+            NetworkCtrlMsg::DelDeviceBulk { new, .. } => (false, Ok(new.network_addrs)),
+            NetworkCtrlMsg::DelDeviceRange { new, .. } => {
+                (false, range_addrs(&new.start_addr, &new.end_addr))
+            }
+        };
+        let addrs = match addrs {
+            Err(e) => {
+                error!("[{}] reject control message: {}", FN_NAME, e);
+                Metrics::inc(&self.metrics.ctrl_rejected);
+                return Ok(());
+            }
+            Ok(addrs) => addrs,
+        };
+
+        info!(
+            "[{}] receive {} {} devices",
+            FN_NAME,
+            match add {
+                false => "delete",
+                true => "add",
+            },
+            addrs.len()
+        );
+
+        let addrs: Vec<String> = addrs.iter().map(|addr| addr.to_lowercase()).collect();
+        {
+            let mut mutex = self.devices.lock().unwrap();
+            for addr in addrs.iter() {
+                match add {
+                    false => (*mutex).remove(addr.as_str()),
+                    true => (*mutex).insert(addr.clone()),
+                };
+            }
+        }
+        if add {
+            return Ok(());
+        }
+
+        // Cancel queuing downlink data of deleted devices.
+        let canceled: Vec<DlData> = {
+            let mut mutex = self.queue_dldata.lock().unwrap();
+            addrs
+                .iter()
+                .filter_map(|addr| (*mutex).remove(addr.as_str()))
+                .flatten()
+                .collect()
+        };
+        for data in canceled {
+            let result = NetDlDataResult {
+                data_id: data.data_id,
+                status: DL_RESULT_CANCELED,
+                message: Some(format!("device {} deleted", data.network_addr)),
+            };
+            if let Err(e) = self.result_buffer.send(mgr, &result, &self.metrics) {
+                error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                Metrics::inc(&self.metrics.publish_errors);
+            }
+        }
 
         Ok(())
     }
 }
+
+/// To list addresses from `start` to `end` (inclusive).
+fn range_addrs(start: &str, end: &str) -> Result<Vec<String>, String> {
+    let start_value = match strings::hex_addr_to_u128(start) {
+        Err(e) => return Err(format!("invalid start address {}: {}", start, e)),
+        Ok(value) => value,
+    };
+    let end_value = match strings::hex_addr_to_u128(end) {
+        Err(e) => return Err(format!("invalid end address {}: {}", end, e)),
+        Ok(value) => value,
+    };
+    if start_value > end_value {
+        return Err(format!("invalid range {}~{}", start, end));
+    } else if end_value - start_value >= MAX_RANGE_ADDRS {
+        return Err(format!(
+            "range {}~{} exceeds {} addresses",
+            start, end, MAX_RANGE_ADDRS
+        ));
+    }
+    Ok((start_value..=end_value)
+        .map(|value| strings::u128_to_addr(value, start.len()))
+        .collect())
+}
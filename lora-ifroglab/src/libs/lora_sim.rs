@@ -0,0 +1,184 @@
+//! A simulated LoRa USB dongle to run the gateway without hardware.
+//!
+//! - Uplink frames of the configured node IDs are generated in turn every interval. The payload
+//!   is the 4-byte sequence number.
+//! - Transmitted frames are recorded into the shared buffer that can be inspected with
+//!   `GET /api/v1/devices/sim/tx`.
+
+use std::{
+    collections::VecDeque,
+    io::{Error as IoError, ErrorKind},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::Serialize;
+use sylvia_iot_sdk::util::strings;
+use tokio::time::Instant;
+
+use super::{
+    fmt_addr,
+    lora_usb::{ChipInfo, ChipValues, LoraPort, ReadData, MAX_FRAME_LEN},
+    FRAME_HEADER_LEN,
+};
+
+/// Options of [`SimLoraPort`].
+#[derive(Clone)]
+pub struct SimOptions {
+    /// Node IDs of generated uplink frames.
+    pub node_ids: Vec<u32>,
+    /// The interval of generated uplink frames in milliseconds.
+    pub interval_ms: u64,
+    /// Transmitted frames of all simulated dongles.
+    pub tx: Arc<Mutex<VecDeque<SimTxData>>>,
+}
+
+/// One transmitted frame of the simulated dongle.
+#[derive(Clone, Serialize)]
+pub struct SimTxData {
+    pub time: String,
+    #[serde(rename = "devPath")]
+    pub dev_path: String,
+    #[serde(rename = "networkAddr")]
+    pub network_addr: String,
+    /// The TX frequency (10kHz).
+    pub freq: u32,
+    /// The payload in hexadecimal string.
+    pub data: String,
+}
+
+/// The simulated dongle that implements [`LoraPort`].
+pub struct SimLoraPort {
+    dev_path: String,
+    opts: SimOptions,
+    values: ChipValues,
+    /// The time to generate the first uplink frame.
+    start: Instant,
+    /// Generated uplink frames that have been read.
+    read: u64,
+}
+
+/// The maximum number of recorded transmitted frames.
+const MAX_TX_DATA: usize = 100;
+/// The firmware version of the simulated dongle.
+const SIM_FW_VER: u8 = 0;
+/// The chip ID of the simulated dongle.
+const SIM_CHIP_ID: u8 = 0;
+
+impl SimLoraPort {
+    /// To create the simulated dongle with initial chip values.
+    pub fn new(dev_path: &str, opts: SimOptions, values: ChipValues) -> Self {
+        SimLoraPort {
+            dev_path: dev_path.to_string(),
+            opts,
+            values,
+            start: Instant::now(),
+            read: 0,
+        }
+    }
+
+    /// Uplink frames that are generated since the start.
+    fn generated(&self) -> u64 {
+        match self.opts.node_ids.is_empty() || self.opts.interval_ms == 0 {
+            false => self.start.elapsed().as_millis() as u64 / self.opts.interval_ms,
+            true => 0,
+        }
+    }
+}
+
+#[async_trait]
+impl LoraPort for SimLoraPort {
+    fn take_crc_errors(&mut self) -> u64 {
+        0
+    }
+
+    async fn cmd00_chip_info(&mut self) -> Result<ChipInfo, IoError> {
+        Ok(ChipInfo {
+            fw_ver: SIM_FW_VER,
+            chip_id: SIM_CHIP_ID,
+            node_id: 0,
+        })
+    }
+
+    async fn ping(&mut self) -> Result<ChipInfo, IoError> {
+        self.cmd00_chip_info().await
+    }
+
+    async fn cmd01_reset(&mut self) -> Result<(), IoError> {
+        self.values.mode = 1;
+        Ok(())
+    }
+
+    async fn cmd02_get_chip_values(&mut self) -> Result<ChipValues, IoError> {
+        Ok(self.values.clone())
+    }
+
+    async fn chip_values_cached(&mut self, _max_age: Duration) -> Result<ChipValues, IoError> {
+        Ok(self.values.clone())
+    }
+
+    async fn cmd03_set_values(&mut self, mode: u8, freq: u32, power: u8) -> Result<(), IoError> {
+        self.values.mode = mode;
+        self.values.freq = freq;
+        self.values.power = power;
+        Ok(())
+    }
+
+    async fn cmd04_set_values(&mut self, bw: u8, cr: u8, sf: u8) -> Result<(), IoError> {
+        self.values.bw = bw;
+        self.values.cr = cr;
+        self.values.sf = sf;
+        Ok(())
+    }
+
+    async fn cmd05_write_data(&mut self, data: &[u8]) -> Result<(), IoError> {
+        if data.len() < FRAME_HEADER_LEN || data.len() > MAX_FRAME_LEN {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("invalid frame length {}", data.len()),
+            ));
+        }
+        let mut node_id = [0u8; 4];
+        node_id.copy_from_slice(&data[0..4]);
+        let tx_data = SimTxData {
+            time: strings::time_str(&Utc::now()),
+            dev_path: self.dev_path.clone(),
+            network_addr: fmt_addr(u32::from_be_bytes(node_id)),
+            freq: self.values.freq,
+            data: hex::encode(&data[FRAME_HEADER_LEN..]),
+        };
+        let mut mutex = self.opts.tx.lock().unwrap();
+        (*mutex).push_back(tx_data);
+        if (*mutex).len() > MAX_TX_DATA {
+            (*mutex).pop_front();
+        }
+        Ok(())
+    }
+
+    async fn cmd06_read_data(&mut self) -> Result<Option<ReadData>, IoError> {
+        if self.values.mode != 3 || self.read >= self.generated() {
+            return Ok(None);
+        }
+        let seq = self.read;
+        self.read += 1;
+        let node_id = self.opts.node_ids[(seq % self.opts.node_ids.len() as u64) as usize];
+        let mut data = Vec::with_capacity(FRAME_HEADER_LEN + 4);
+        data.extend_from_slice(&node_id.to_be_bytes());
+        data.extend_from_slice(&[0u8; FRAME_HEADER_LEN - 4]);
+        data.extend_from_slice(&(seq as u32).to_be_bytes());
+        Ok(Some(ReadData {
+            data,
+            rssi: -40 - (seq % 50) as i16,
+        }))
+    }
+
+    async fn cmd07_read_data_counter(&mut self) -> Result<u16, IoError> {
+        Ok(self.generated() as u16)
+    }
+
+    async fn reopen(self: Box<Self>) -> Result<Box<dyn LoraPort>, (IoError, String)> {
+        Ok(self)
+    }
+}
@@ -1,39 +1,160 @@
 //! LoRa task handles LoRa gateway RX operations:
+//! - Use a port task to own the serial port and execute commands from the RX and TX tasks.
 //! - Use a timer task to poll new RX data.
 //! - Send the new RX data as an uplink data to the `uldata` queue.
-//! - Pop one queued downlink data and send TX data to the device.
+//! - Pop queued downlink data (at most `max_tx_per_window`) and send TX data to the device.
+//! - Pop one queued broadcast downlink data and send TX data to all devices.
+//! - In the immediate TX mode, send queued downlink data without waiting for uplink data.
+//! - Use another timer task to remove expired downlink data from the queue.
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
     error::Error as StdError,
+    hash::{Hash, Hasher},
     io::{Error as IoError, ErrorKind},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
-use chrono::Utc;
+use chrono::{DateTime, TimeDelta, Utc};
 use hex;
 use log::{error, info, warn};
 use serde_json::{json, Map};
 use sylvia_iot_sdk::{
-    mq::network::{DlDataResult, NetworkMgr, UlData as NetUlData},
+    mq::{
+        network::{DlDataResult, NetworkMgr, UlData as NetUlData},
+        MgrStatus,
+    },
     util::strings,
 };
 use tokio::{
+    sync::{mpsc, oneshot, Notify},
     task::{self, JoinHandle},
-    time,
+    time::{self, Instant},
 };
+use tokio_util::sync::{CancellationToken, DropGuard};
 
-use super::{lora_usb::IfroglabLora, DlData, UlData, UlDataExt, MAX_DATA};
+use super::{
+    ack_tracker::{AckTimeout, AckTracker},
+    crypto::{self, NonceCounters},
+    fmt_addr,
+    lora_sim::{SimLoraPort, SimOptions},
+    lora_usb::{
+        self, BuildError, ChipInfo, ChipValues, IfroglabLora, IfroglabLoraBuilder,
+        IfroglabLoraOptions, LoraPort, ReadData, UsbFilter,
+    },
+    metrics::{HealthCheck, Metrics},
+    queue_file,
+    result_buffer::ResultBuffer,
+    ul_decoder::UlDecoders,
+    DlData, NodeEvent, NodeStatus, TokenBucket, UlData, UlDataExt, BROADCAST_ADDR,
+    DL_RESULT_CANCELED, DL_RESULT_EXPIRED, DL_RESULT_INVALID, DL_RESULT_SENT,
+    DL_RESULT_TRANSMITTED, DL_RESULT_TX_FAILED, DL_RESULT_UNDELIVERED, FRAME_HEADER_LEN, MAX_DATA,
+    MAX_DL_PAYLOAD, NODE_EVENT_OFFLINE, NODE_EVENT_ONLINE,
+};
 
 pub struct Options {
+    /// The dongle index. The first dongle (index 0) also runs the sweep and save tasks, and sends
+    /// broadcast data and data of addresses that are not heard in the immediate TX mode.
+    pub index: usize,
+    /// The dongle index that last heard each address. This is shared by all LoRa tasks.
+    pub addr_dongles: Arc<Mutex<HashMap<String, usize>>>,
+    /// The connected device path of each dongle index. This is shared by all LoRa tasks to prevent
+    /// detecting the same port.
+    pub port_paths: Arc<Mutex<HashMap<usize, String>>>,
     pub mgr: Arc<Mutex<NetworkMgr>>,
     pub latest_uldata: Arc<Mutex<VecDeque<UlData>>>,
     pub latest_dldata: Arc<Mutex<VecDeque<DlData>>>,
     pub queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>,
+    /// TX budgets of nodes with `node_tx_per_minute`. This is shared by all LoRa tasks.
+    pub tx_budget: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    /// Downlink nonce counters of nodes with keys. This is shared by all LoRa tasks.
+    pub dl_nonces: Arc<NonceCounters>,
+    pub devices: Arc<Mutex<HashSet<String>>>,
+    /// The status of each heard address. This is shared by all LoRa tasks and the data
+    /// handler.
+    pub nodes: Arc<Mutex<HashMap<String, NodeStatus>>>,
+    /// Offline and online events of nodes. This is shared by all LoRa tasks.
+    pub events: Arc<Mutex<VecDeque<NodeEvent>>>,
+    /// Uplink data buffered while the message queue is not ready. This is shared with the data
+    /// handler that sends buffered data when the message queue is ready.
+    pub offline_uldata: Arc<Mutex<VecDeque<NetUlData>>>,
+    /// Transmitted downlink data waiting for acknowledgments. This is shared by all LoRa tasks.
+    /// `None` to disable acknowledgment tracking.
+    pub ack_tracker: Option<Arc<Mutex<AckTracker>>>,
+    /// Downlink data results that cannot be sent to the broker. This is shared by all LoRa tasks
+    /// and the data handler.
+    pub result_buffer: Arc<ResultBuffer>,
+    pub metrics: Arc<Metrics>,
     pub dev_path: String,
     pub freq: u32,
+    /// TX frequencies in round-robin. Empty to transmit with `freq`.
+    pub freq_list: Vec<u32>,
     pub power: u8,
+    /// Bandwidth. 1~3 for 125k, 250k, 500k.
+    pub bw: u8,
+    /// Code rate. 1~4 for 4/5, 4/6, 4/7, 4/8.
+    pub cr: u8,
+    /// Spreading factor. 1~7 for SF6~SF12.
+    pub sf: u8,
+    pub poll_interval_ms: u64,
+    /// Time-to-live of queued downlink data in seconds. `0` means never expire.
+    pub dldata_ttl_secs: u64,
+    /// Retry times when failed to transmit downlink data.
+    pub tx_retries: u32,
+    /// Maximum downlink payload size in bytes. 1~8.
+    pub max_dl_payload_bytes: usize,
+    /// Maximum uplink data to be buffered while the message queue is not ready. `0` to disable
+    /// buffering.
+    pub offline_buffer_size: usize,
+    /// Maximum downlink data to be sent to the node after receiving one uplink data.
+    pub max_tx_per_window: usize,
+    /// Reconnect the port after the number of consecutive command failures.
+    pub reconnect_failures: usize,
+    /// Reset the dongle after the number of consecutive invalid data (such as CRC) errors.
+    pub reset_failures: usize,
+    /// Check the dongle with [`LoraPort::ping()`] after no successful command in seconds. `0` to
+    /// disable.
+    pub health_check_secs: u64,
+    /// Options of the dongle ACK handling.
+    pub port_opts: IfroglabLoraOptions,
+    /// The USB filter to detect the dongle when `dev_path` is `auto`.
+    pub usb_filter: UsbFilter,
+    /// Uplink data with RSSI less than this value will not be sent to the broker.
+    pub min_rssi: Option<i16>,
+    /// Identical uplink frames of one node within this window (ms) will not be sent to the broker.
+    pub dedup_ms: u64,
+    /// To align RX polling to multiples of this value (ms) of the wall clock.
+    pub align_ms: Option<u64>,
+    /// Maximum downlink data to be transmitted to one node per minute. `None` means unlimited.
+    pub node_tx_per_minute: Option<u32>,
+    /// Maximum nodes in `nodes`.
+    pub max_nodes: usize,
+    /// A node is offline when it is not heard in this time (seconds). `None` to disable offline
+    /// detection.
+    pub node_offline_secs: Option<u64>,
+    /// To publish offline events as uplink data and mark the next uplink data as online.
+    pub publish_node_events: bool,
+    /// AES-128 keys (hexadecimal) of nodes. The key of the map is the network address.
+    pub keys: HashMap<String, String>,
+    /// Uplink decoders of nodes.
+    pub decoders: UlDecoders,
+    /// To drop uplink data from devices that are not added by the broker.
+    pub whitelist_only: bool,
+    /// The file to persist queuing downlink data.
+    pub queue_file: Option<String>,
+    /// To report queuing downlink data as canceled when shutting down.
+    pub cancel_on_shutdown: bool,
+    /// To send queuing data of other nodes in round-robin when the node of the uplink data has no
+    /// queuing data. This is for the `afterRx` TX mode.
+    pub fair_tx: bool,
+    /// To use the simulated dongle instead of the USB dongle.
+    pub sim: Option<SimOptions>,
+    /// To be notified when new downlink data is queued. `None` for sending data after RX.
+    pub tx_notify: Option<Arc<Notify>>,
 }
 
 #[derive(Clone)]
@@ -41,22 +162,102 @@ pub struct LoraTask {
     opts: OptionsInner,
 
     queue_rsc: QueueRsc,
-    task_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    port: PortClient,
+    port_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    rx_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    tx_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    sweep_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    save_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    ack_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// The index of the next TX frequency in `freq_list`.
+    tx_channel: Arc<AtomicUsize>,
+    watchdog_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    result_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    cancel: CancellationToken,
+    /// Cancel all tasks when all clones (except clones in runtime tasks) are dropped.
+    _guard: Option<Arc<DropGuard>>,
 }
 
 #[derive(Clone)]
 struct QueueRsc {
+    addr_dongles: Arc<Mutex<HashMap<String, usize>>>,
+    port_paths: Arc<Mutex<HashMap<usize, String>>>,
     mgr: Arc<Mutex<NetworkMgr>>,
     latest_uldata: Arc<Mutex<VecDeque<UlData>>>,
     latest_dldata: Arc<Mutex<VecDeque<DlData>>>,
     queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>,
+    tx_budget: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    dl_nonces: Arc<NonceCounters>,
+    devices: Arc<Mutex<HashSet<String>>>,
+    nodes: Arc<Mutex<HashMap<String, NodeStatus>>>,
+    events: Arc<Mutex<VecDeque<NodeEvent>>>,
+    offline_uldata: Arc<Mutex<VecDeque<NetUlData>>>,
+    ack_tracker: Option<Arc<Mutex<AckTracker>>>,
+    result_buffer: Arc<ResultBuffer>,
+    metrics: Arc<Metrics>,
+    tx_notify: Option<Arc<Notify>>,
 }
 
 #[derive(Clone)]
 struct OptionsInner {
+    index: usize,
     dev_path: String,
     freq: u32,
+    freq_list: Vec<u32>,
     power: u8,
+    bw: u8,
+    cr: u8,
+    sf: u8,
+    poll_interval_ms: u64,
+    dldata_ttl_secs: u64,
+    tx_retries: u32,
+    max_dl_payload_bytes: usize,
+    offline_buffer_size: usize,
+    max_tx_per_window: usize,
+    reconnect_failures: usize,
+    reset_failures: usize,
+    health_check_secs: u64,
+    port_opts: IfroglabLoraOptions,
+    usb_filter: UsbFilter,
+    min_rssi: Option<i16>,
+    dedup_ms: u64,
+    align_ms: Option<u64>,
+    node_tx_per_minute: Option<u32>,
+    max_nodes: usize,
+    node_offline_secs: Option<u64>,
+    publish_node_events: bool,
+    keys: HashMap<String, [u8; crypto::KEY_LEN]>,
+    decoders: UlDecoders,
+    whitelist_only: bool,
+    queue_file: Option<String>,
+    cancel_on_shutdown: bool,
+    fair_tx: bool,
+    sim: Option<SimOptions>,
+}
+
+/// Commands of the port task. Responses are sent back with the oneshot channels.
+enum PortCmd {
+    /// To get the RX data counter and the number of new RX frames since the last poll with command
+    /// 0x07.
+    PollCounter(oneshot::Sender<Result<(u16, u16), IoError>>),
+    /// To read pending RX frames (at most the specified number) with command 0x06.
+    ReadAll(usize, oneshot::Sender<Result<Vec<ReadData>, IoError>>),
+    /// To transmit one frame and then switch back to RX mode.
+    Transmit(Vec<u8>, u32, oneshot::Sender<Result<(), IoError>>),
+    /// To get the cached chip values that are not older than the specified age.
+    ChipValues(Duration, oneshot::Sender<Result<ChipValues, IoError>>),
+}
+
+/// The client to send commands to the port task.
+#[derive(Clone)]
+struct PortClient {
+    cmd_tx: mpsc::Sender<PortCmd>,
+}
+
+/// The last published uplink frame (payload hash, time in milliseconds) of each address.
+struct DedupCache {
+    window_ms: i64,
+    frames: HashMap<String, (u64, i64)>,
 }
 
 struct RxData {
@@ -64,214 +265,1732 @@ struct RxData {
     payload: Vec<u8>,
 }
 
-const SLEEP_IDLE_MS: u64 = 100;
+const SWEEP_INTERVAL_MS: u64 = 1000;
+/// The minimum interval to send buffered downlink data results.
+const RESULT_RETRY_MIN_MS: u64 = 1000;
+/// The maximum interval to send buffered downlink data results.
+const RESULT_RETRY_MAX_MS: u64 = 60000;
+/// The interval to check offline nodes.
+const WATCHDOG_INTERVAL_MS: u64 = 5000;
+/// The interval to save the changed downlink queue into the file.
+const SAVE_INTERVAL_MS: u64 = 1000;
+/// The interval between two downlink frames in the same RX window.
+const TX_INTERVAL_MS: u64 = 50;
+/// The time to wait for each runtime task when shutting down.
+const SHUTDOWN_TIMEOUT_MS: u64 = 3000;
+/// The minimum number of RX frames to be read in one drain.
+const RX_DRAIN_MAX: usize = 32;
+/// The command queue size of the port task.
+const PORT_CMD_QUEUE: usize = 16;
 
 impl LoraTask {
     pub fn new(opts: Options) -> Result<Self, Box<dyn StdError>> {
+        check_values(&opts)?;
+        let keys = parse_keys(&opts.keys)?;
+        let cancel = CancellationToken::new();
+        let (cmd_tx, cmd_rx) = mpsc::channel(PORT_CMD_QUEUE);
+
         let queue_rsc = QueueRsc {
+            addr_dongles: opts.addr_dongles,
+            port_paths: opts.port_paths,
             mgr: opts.mgr,
             latest_uldata: opts.latest_uldata,
             latest_dldata: opts.latest_dldata,
             queue_dldata: opts.queue_dldata,
+            tx_budget: opts.tx_budget,
+            dl_nonces: opts.dl_nonces,
+            devices: opts.devices,
+            nodes: opts.nodes,
+            events: opts.events,
+            offline_uldata: opts.offline_uldata,
+            ack_tracker: opts.ack_tracker,
+            result_buffer: opts.result_buffer,
+            metrics: opts.metrics,
+            tx_notify: opts.tx_notify,
         };
 
         let task = LoraTask {
             opts: OptionsInner {
+                index: opts.index,
                 dev_path: opts.dev_path,
                 freq: opts.freq,
+                freq_list: opts.freq_list,
                 power: opts.power,
+                bw: opts.bw,
+                cr: opts.cr,
+                sf: opts.sf,
+                poll_interval_ms: opts.poll_interval_ms,
+                dldata_ttl_secs: opts.dldata_ttl_secs,
+                tx_retries: opts.tx_retries,
+                max_dl_payload_bytes: opts.max_dl_payload_bytes,
+                offline_buffer_size: opts.offline_buffer_size,
+                max_tx_per_window: opts.max_tx_per_window,
+                reconnect_failures: opts.reconnect_failures,
+                reset_failures: opts.reset_failures,
+                health_check_secs: opts.health_check_secs,
+                port_opts: opts.port_opts,
+                usb_filter: opts.usb_filter,
+                min_rssi: opts.min_rssi,
+                dedup_ms: opts.dedup_ms,
+                align_ms: opts.align_ms,
+                node_tx_per_minute: opts.node_tx_per_minute,
+                max_nodes: opts.max_nodes,
+                node_offline_secs: opts.node_offline_secs,
+                publish_node_events: opts.publish_node_events,
+                keys,
+                decoders: opts.decoders,
+                whitelist_only: opts.whitelist_only,
+                queue_file: opts.queue_file,
+                cancel_on_shutdown: opts.cancel_on_shutdown,
+                fair_tx: opts.fair_tx,
+                sim: opts.sim,
             },
             queue_rsc,
-            task_handle: Arc::new(Mutex::new(None)),
+            port: PortClient { cmd_tx },
+            port_handle: Arc::new(Mutex::new(None)),
+            rx_handle: Arc::new(Mutex::new(None)),
+            tx_handle: Arc::new(Mutex::new(None)),
+            sweep_handle: Arc::new(Mutex::new(None)),
+            save_handle: Arc::new(Mutex::new(None)),
+            ack_handle: Arc::new(Mutex::new(None)),
+            tx_channel: Arc::new(AtomicUsize::new(0)),
+            watchdog_handle: Arc::new(Mutex::new(None)),
+            result_handle: Arc::new(Mutex::new(None)),
+            cancel: cancel.clone(),
+            _guard: Some(Arc::new(cancel.drop_guard())),
         };
         {
-            *task.task_handle.lock().unwrap() = Some(create_event_loop(task.clone()));
+            let (rx_addr_tx, rx_addr_rx) = mpsc::unbounded_channel();
+            let port = task.port.clone();
+            *task.port_handle.lock().unwrap() = Some(create_port_loop(task.worker(), cmd_rx));
+            *task.rx_handle.lock().unwrap() =
+                Some(create_rx_loop(task.worker(), port.clone(), rx_addr_tx));
+            *task.tx_handle.lock().unwrap() = Some(create_tx_loop(task.worker(), port, rx_addr_rx));
+        }
+        if task.opts.index > 0 {
+            return Ok(task);
+        }
+        if task.opts.dldata_ttl_secs > 0 {
+            *task.sweep_handle.lock().unwrap() = Some(create_sweep_loop(task.worker()));
+        }
+        if let Some(path) = task.opts.queue_file.as_ref() {
+            *task.save_handle.lock().unwrap() = Some(create_save_loop(task.worker(), path.clone()));
+        }
+        if task.queue_rsc.ack_tracker.is_some() {
+            *task.ack_handle.lock().unwrap() = Some(create_ack_loop(task.worker()));
+        }
+        *task.result_handle.lock().unwrap() = Some(create_result_loop(task.worker()));
+        if let Some(secs) = task.opts.node_offline_secs {
+            *task.watchdog_handle.lock().unwrap() = Some(create_watchdog_loop(task.worker(), secs));
         }
         Ok(task)
     }
+
+    /// To get the chip values of the dongle. The cached values will be used if they are not older
+    /// than `max_age` to prevent disturbing RX polling.
+    pub async fn chip_values(&self, max_age: Duration) -> Result<ChipValues, IoError> {
+        self.port.chip_values(max_age).await
+    }
+
+    /// To stop all runtime tasks. The dongle will be set to standby mode after finishing the current
+    /// serial command. Queuing downlink data will be reported as canceled if `cancel_on_shutdown`
+    /// is enabled, and saved into the queue file if specified.
+    pub async fn shutdown(&self) {
+        const FN_NAME: &'static str = "LoraTask::shutdown";
+
+        self.cancel.cancel();
+        let handles = [
+            &self.rx_handle,
+            &self.tx_handle,
+            &self.port_handle,
+            &self.sweep_handle,
+            &self.save_handle,
+            &self.ack_handle,
+            &self.watchdog_handle,
+            &self.result_handle,
+        ];
+        for handle in handles {
+            let handle = { handle.lock().unwrap().take() };
+            if let Some(mut handle) = handle {
+                let timeout = Duration::from_millis(SHUTDOWN_TIMEOUT_MS);
+                if time::timeout(timeout, &mut handle).await.is_err() {
+                    warn!("[{}] abort task after timeout", FN_NAME);
+                    handle.abort();
+                }
+            }
+        }
+        if self.opts.index > 0 {
+            return;
+        }
+
+        if self.opts.cancel_on_shutdown {
+            let canceled: Vec<DlData> = {
+                let mut mutex = self.queue_rsc.queue_dldata.lock().unwrap();
+                (*mutex).drain().flat_map(|(_, queue)| queue).collect()
+            };
+            for data in canceled {
+                let result = DlDataResult {
+                    data_id: data.data_id,
+                    status: DL_RESULT_CANCELED,
+                    message: Some("gateway shutdown".to_string()),
+                };
+                if let Err(e) = send_result(self, &result) {
+                    error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                }
+            }
+        }
+        if let Some(path) = self.opts.queue_file.as_ref() {
+            let content = {
+                let mutex = self.queue_rsc.queue_dldata.lock().unwrap();
+                queue_file::serialize(&mutex)
+            };
+            let result = match content {
+                Err(e) => Err(e),
+                Ok(content) => queue_file::save(path.as_str(), content.as_str()),
+            };
+            if let Err(e) = result {
+                error!("[{}] save {} error: {}", FN_NAME, path, e);
+            }
+        }
+    }
+
+    /// To clone the task for runtime tasks without the drop guard.
+    fn worker(&self) -> Self {
+        LoraTask {
+            _guard: None,
+            ..self.clone()
+        }
+    }
 }
 
-/// To create an event loop runtime task.
-fn create_event_loop(task: LoraTask) -> JoinHandle<()> {
+/// To create the port task that owns the serial port and executes [`PortCmd`] one by one.
+///
+/// The dongle will be reset after `reset_failures` consecutive invalid data errors. After
+/// `reconnect_failures` consecutive command failures, the dongle will be recovered once, and then
+/// the port will be reconnected if the recovery fails or commands still fail.
+///
+/// The dongle will be checked after `health_check_secs` without successful commands, and the port
+/// will be reconnected if the check fails.
+fn create_port_loop(task: LoraTask, mut cmd_rx: mpsc::Receiver<PortCmd>) -> JoinHandle<()> {
     task::spawn(async move {
-        const FN_NAME: &'static str = "event_loop";
-        let sleep_time = SLEEP_IDLE_MS;
+        const FN_NAME: &'static str = "port_loop";
+        let metrics = task.queue_rsc.metrics.as_ref();
         // Connect to the USB dongle.
-        let (mut port, mut counter) = loop {
-            time::sleep(Duration::from_millis(SLEEP_IDLE_MS)).await;
-            let mut port = match IfroglabLora::new(task.opts.dev_path.as_str()) {
-                Err(e) => {
-                    error!("[{}] create port error: {}", FN_NAME, e);
-                    continue;
-                }
-                Ok(port) => port,
+        let (mut port, mut counter) = tokio::select! {
+            _ = task.cancel.cancelled() => return,
+            result = connect_port(&task) => result,
+        };
+        // Consecutive command failures.
+        let mut failures = 0;
+        // Consecutive invalid data errors.
+        let mut invalid_data = 0;
+        // The dongle has been recovered since the last success command.
+        let mut recovered = false;
+        // The time of the last success command.
+        let mut last_ok = Instant::now();
+        let health_check = Duration::from_secs(task.opts.health_check_secs);
+        loop {
+            // `None` means to check the dongle health.
+            let cmd = tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                cmd = cmd_rx.recv() => match cmd {
+                    None => break,
+                    Some(cmd) => Some(cmd),
+                },
+                _ = time::sleep_until(last_ok + health_check),
+                        if task.opts.health_check_secs > 0 => None,
             };
-            if let Err(e) = port
-                .cmd03_set_values(3, task.opts.freq, task.opts.power)
-                .await
-            {
-                error!("[{}] set RX mode error: {}", FN_NAME, e);
-                continue;
+            if failures >= task.opts.reconnect_failures && !recovered {
+                // Try to recover the dongle once before reopening the port.
+                warn!(
+                    "[{}] recover dongle after {} consecutive failures",
+                    FN_NAME, failures
+                );
+                recovered = true;
+                Metrics::inc(&metrics.resets);
+                match reset_port(&task, port.as_mut()).await {
+                    Err(e) => error!("[{}] recover dongle error: {}, reconnect", FN_NAME, e),
+                    Ok(new_counter) => {
+                        counter = new_counter;
+                        failures = 0;
+                        invalid_data = 0;
+                        last_ok = Instant::now();
+                    }
+                }
             }
-            let counter = match port.cmd07_read_data_counter().await {
-                Err(e) => {
-                    error!("[{}] get counter error: {}", FN_NAME, e);
+            if failures >= task.opts.reconnect_failures {
+                warn!(
+                    "[{}] reconnect port after {} consecutive failures",
+                    FN_NAME, failures
+                );
+                Metrics::inc(&metrics.reconnects);
+                (port, counter) = match reopen_port(port).await {
+                    Some(result) => result,
+                    None => tokio::select! {
+                        _ = task.cancel.cancelled() => return,
+                        result = connect_port(&task) => result,
+                    },
+                };
+                failures = 0;
+                invalid_data = 0;
+                recovered = false;
+                last_ok = Instant::now();
+            }
+            let cmd = match cmd {
+                None => {
+                    if let Err(e) = check_health(&task, port.as_mut()).await {
+                        warn!("[{}] health check error: {}, reconnect", FN_NAME, e);
+                        failures = task.opts.reconnect_failures;
+                        recovered = true;
+                    } else {
+                        failures = 0;
+                        invalid_data = 0;
+                        recovered = false;
+                    }
+                    last_ok = Instant::now();
                     continue;
                 }
-                Ok(counter) => counter,
+                Some(cmd) => cmd,
             };
-            break (port, counter);
-        };
-        info!("[{}] connected to port", FN_NAME);
-        // Main loop.
+            let err_kind = match cmd {
+                PortCmd::PollCounter(resp) => match port.cmd07_read_data_counter().await {
+                    Err(e) => {
+                        let kind = e.kind();
+                        let _ = resp.send(Err(e));
+                        Some(kind)
+                    }
+                    Ok(new_counter) => {
+                        let delta = counter_delta(counter, new_counter);
+                        counter = new_counter;
+                        let _ = resp.send(Ok((new_counter, delta)));
+                        None
+                    }
+                },
+                PortCmd::ReadAll(max, resp) => {
+                    let result = port.cmd06_read_all(max).await;
+                    let kind = result.as_ref().err().map(|e| e.kind());
+                    let _ = resp.send(result);
+                    kind
+                }
+                PortCmd::Transmit(frame, freq, resp) => {
+                    let result = transmit(port.as_mut(), &task.opts, freq, frame.as_slice()).await;
+                    let kind = result.as_ref().err().map(|e| e.kind());
+                    let _ = resp.send(result);
+                    kind
+                }
+                PortCmd::ChipValues(max_age, resp) => {
+                    let result = port.chip_values_cached(max_age).await;
+                    let kind = result.as_ref().err().map(|e| e.kind());
+                    let _ = resp.send(result);
+                    kind
+                }
+            };
+            match err_kind {
+                None => {
+                    failures = 0;
+                    invalid_data = 0;
+                    recovered = false;
+                    last_ok = Instant::now();
+                }
+                Some(kind) => {
+                    failures += 1;
+                    invalid_data = match kind {
+                        ErrorKind::InvalidData => invalid_data + 1,
+                        _ => 0,
+                    };
+                }
+            }
+            if invalid_data >= task.opts.reset_failures {
+                warn!(
+                    "[{}] reset dongle after {} consecutive invalid data errors",
+                    FN_NAME, invalid_data
+                );
+                invalid_data = 0;
+                Metrics::inc(&metrics.resets);
+                match reset_port(&task, port.as_mut()).await {
+                    Err(e) => {
+                        error!("[{}] reset dongle error: {}, reconnect", FN_NAME, e);
+                        failures = task.opts.reconnect_failures;
+                        recovered = true;
+                    }
+                    Ok(new_counter) => {
+                        counter = new_counter;
+                        failures = 0;
+                    }
+                }
+            }
+            metrics
+                .crc_errors
+                .fetch_add(port.take_crc_errors(), Ordering::Relaxed);
+        }
+
+        // Set the dongle to standby mode.
+        if let Err(e) = port
+            .cmd03_set_values(1, task.opts.freq, task.opts.power)
+            .await
+        {
+            error!("[{}] set standby mode error: {}", FN_NAME, e);
+        }
+        info!("[{}] stopped", FN_NAME);
+    })
+}
+
+/// To create the RX polling task. Uplink data will be sent to the broker, and the addresses will be
+/// sent to the TX task in the `afterRx` TX mode.
+fn create_rx_loop(
+    task: LoraTask,
+    port: PortClient,
+    rx_addr_tx: mpsc::UnboundedSender<String>,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        const FN_NAME: &'static str = "rx_loop";
+        let metrics = task.queue_rsc.metrics.as_ref();
+        let mut dedup = DedupCache::new(task.opts.dedup_ms);
         loop {
-            time::sleep(Duration::from_millis(sleep_time)).await;
-            counter = match port.cmd07_read_data_counter().await {
+            let sleep_time = match task.opts.align_ms {
+                None | Some(0) => task.opts.poll_interval_ms,
+                Some(align_ms) => align_sleep_ms(Utc::now().timestamp_millis(), align_ms),
+            };
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(sleep_time)) => {}
+            }
+            let (counter, delta) = match port.poll_counter().await {
                 Err(e) => {
                     error!("[{}] get counter error: {}", FN_NAME, e);
                     continue;
                 }
-                Ok(new_counter) => match counter == new_counter {
-                    false => new_counter,
-                    true => continue,
+                Ok((counter, delta)) => match delta {
+                    0 => continue,
+                    delta => (counter, delta),
                 },
             };
-            let read_data = match port.cmd06_read_data().await {
+            metrics
+                .rx_counted
+                .fetch_add(delta as u64, Ordering::Relaxed);
+
+            // Drain all pending frames including frames implied by the counter increments.
+            let max = RX_DRAIN_MAX.max(delta as usize);
+            let frames = match port.read_all(max).await {
                 Err(e) => {
-                    error!("[{}] get counter error: {}", FN_NAME, e);
-                    continue;
+                    error!("[{}] read data error: {}", FN_NAME, e);
+                    vec![]
                 }
-                Ok(data) => match data {
-                    None => continue,
-                    Some(data) => data,
-                },
+                Ok(frames) => frames,
             };
+            if !frames.is_empty() {
+                Metrics::inc(&metrics.rx_drains);
+            }
+            metrics
+                .rx_read
+                .fetch_add(frames.len() as u64, Ordering::Relaxed);
+            if frames.len() < delta as usize {
+                warn!("[{}] read {}/{} frames", FN_NAME, frames.len(), delta);
+            }
+            for read_data in frames {
+                let addr = match handle_rxdata(&task, &mut dedup, counter, read_data) {
+                    None => continue,
+                    Some(addr) => addr,
+                };
+                if task.queue_rsc.tx_notify.is_none() && rx_addr_tx.send(addr).is_err() {
+                    error!("[{}] TX task closed", FN_NAME);
+                }
+            }
+        }
+    })
+}
 
-            // Send uplink data to the broker.
-            let rx_data = match parse_rx_data(read_data.data.as_slice()) {
+/// To create the TX dispatch task.
+/// - `afterRx`: send queued downlink data of the address received from the RX task.
+/// - `immediate`: send all queued downlink data when notified or periodically for retries.
+fn create_tx_loop(
+    task: LoraTask,
+    port: PortClient,
+    mut rx_addr_rx: mpsc::UnboundedReceiver<String>,
+) -> JoinHandle<()> {
+    task::spawn(async move {
+        let notify = match task.queue_rsc.tx_notify.clone() {
+            None => {
+                // The last address served by fair TX.
+                let mut fair_addr = String::new();
+                loop {
+                    let addr = tokio::select! {
+                        _ = task.cancel.cancelled() => break,
+                        addr = rx_addr_rx.recv() => match addr {
+                            None => break,
+                            Some(addr) => addr,
+                        },
+                    };
+                    // Send downlink data to the node if there are queued data to be send.
+                    let sent = send_window(&task, &port, addr.as_str()).await;
+                    // Send one downlink data of other nodes if the node has no queued data.
+                    if sent == 0 && task.opts.fair_tx && addr.as_str() != BROADCAST_ADDR {
+                        if let Some(data) = pop_fair_dldata(&task, &mut fair_addr) {
+                            send_dldata(&task, &port, data).await;
+                        }
+                    }
+                    // Send one broadcast downlink data if there are queued data to be send.
+                    if addr.as_str() != BROADCAST_ADDR {
+                        if let Some(data) = pop_dldata(&task, BROADCAST_ADDR) {
+                            send_dldata(&task, &port, data).await;
+                        }
+                    }
+                }
+                return;
+            }
+            Some(notify) => notify,
+        };
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(task.opts.poll_interval_ms)) => {}
+                _ = notify.notified() => {}
+            }
+            flush_dldata(&task, &port).await;
+        }
+    })
+}
+
+/// To send the uplink data to the broker. Data will be buffered while the message queue is not
+/// ready or sending fails, and buffered data will be sent first when the message queue is ready to
+/// keep the order. The oldest data will be dropped if the buffer is full.
+///
+/// Returns `false` if the data is buffered.
+fn publish_uldata(task: &LoraTask, uldata: NetUlData) -> Result<bool, Box<dyn StdError>> {
+    const FN_NAME: &'static str = "publish_uldata";
+
+    if task.opts.offline_buffer_size == 0 {
+        task.queue_rsc.mgr.lock().unwrap().send_uldata(&uldata)?;
+        return Ok(true);
+    }
+
+    let metrics = task.queue_rsc.metrics.as_ref();
+    let mut buffer = task.queue_rsc.offline_uldata.lock().unwrap();
+    {
+        let mgr = task.queue_rsc.mgr.lock().unwrap();
+        if mgr.status() == MgrStatus::Ready {
+            // Send buffered data first to keep the order.
+            while let Some(data) = (*buffer).pop_front() {
+                if let Err(e) = mgr.send_uldata(&data) {
+                    error!("[{}] send buffered data error: {}", FN_NAME, e);
+                    Metrics::inc(&metrics.publish_errors);
+                    (*buffer).push_front(data);
+                    break;
+                }
+                Metrics::inc(&metrics.uldata_published);
+            }
+            if (*buffer).is_empty() {
+                match mgr.send_uldata(&uldata) {
+                    Err(e) => {
+                        // Keep the data to be sent with buffered data later.
+                        error!("[{}] send uldata message error: {}, buffer", FN_NAME, e);
+                        Metrics::inc(&metrics.publish_errors);
+                    }
+                    Ok(()) => return Ok(true),
+                }
+            }
+        }
+    }
+    if (*buffer).len() >= task.opts.offline_buffer_size {
+        if let Some(data) = (*buffer).pop_front() {
+            warn!(
+                "[{}] drop buffered data from {}",
+                FN_NAME, data.network_addr
+            );
+            Metrics::inc(&metrics.uldata_buffer_dropped);
+        }
+    }
+    (*buffer).push_back(uldata);
+    Ok(false)
+}
+
+/// To send buffered uplink data in order when the message queue is ready. The remaining data will
+/// be kept if sending fails.
+///
+/// Returns `false` if there are remaining data.
+fn flush_uldata(task: &LoraTask) -> bool {
+    const FN_NAME: &'static str = "flush_uldata";
+
+    let metrics = task.queue_rsc.metrics.as_ref();
+    let mut buffer = task.queue_rsc.offline_uldata.lock().unwrap();
+    if (*buffer).is_empty() {
+        return true;
+    }
+    let mgr = task.queue_rsc.mgr.lock().unwrap();
+    if mgr.status() != MgrStatus::Ready {
+        return false;
+    }
+    while let Some(data) = (*buffer).pop_front() {
+        if let Err(e) = mgr.send_uldata(&data) {
+            warn!("[{}] send buffered data error: {}", FN_NAME, e);
+            Metrics::inc(&metrics.publish_errors);
+            (*buffer).push_front(data);
+            return false;
+        }
+        Metrics::inc(&metrics.uldata_published);
+    }
+    true
+}
+
+/// To check the dongle health with [`LoraPort::ping()`] and record the result in metrics.
+async fn check_health(task: &LoraTask, port: &mut dyn LoraPort) -> Result<(), IoError> {
+    let metrics = task.queue_rsc.metrics.as_ref();
+    Metrics::inc(&metrics.health_checks);
+    let result = port.ping().await;
+    let error = match result.as_ref() {
+        Err(e) => {
+            Metrics::inc(&metrics.health_check_errors);
+            Some(e.to_string())
+        }
+        Ok(_) => None,
+    };
+    *metrics.last_health_check.lock().unwrap() = Some(HealthCheck {
+        time: Utc::now(),
+        error,
+    });
+    result.map(|_| ())
+}
+
+/// To handle one RX frame and send the uplink data to the broker.
+///
+/// `counter` is the RX data counter when reading the frame.
+///
+/// Returns the node address if the uplink data is sent to the broker.
+fn handle_rxdata(
+    task: &LoraTask,
+    dedup: &mut DedupCache,
+    counter: u16,
+    read_data: ReadData,
+) -> Option<String> {
+    const FN_NAME: &'static str = "handle_rxdata";
+    let metrics = task.queue_rsc.metrics.as_ref();
+
+    // Send uplink data to the broker.
+    let rx_data = match parse_rx_data(read_data.data.as_slice()) {
+        Err(e) => {
+            warn!("[{}] get counter error: {}", FN_NAME, e);
+            return None;
+        }
+        Ok(data) => data,
+    };
+    Metrics::inc(&metrics.uldata_received);
+    let addr = fmt_addr(rx_data.node_id);
+    if task.opts.whitelist_only {
+        let known = {
+            let mutex = task.queue_rsc.devices.lock().unwrap();
+            (*mutex).contains(addr.as_str())
+        };
+        if !known {
+            warn!("[{}] drop data from unknown device {}", FN_NAME, addr);
+            Metrics::inc(&metrics.uldata_unknown);
+            return None;
+        }
+    }
+    {
+        let mut mutex = metrics.last_rssi.lock().unwrap();
+        (*mutex).insert(addr.clone(), read_data.rssi);
+    }
+    {
+        let mut mutex = task.queue_rsc.addr_dongles.lock().unwrap();
+        (*mutex).insert(addr.clone(), task.opts.index);
+    }
+    let now = Utc::now();
+    let online = {
+        let mut mutex = task.queue_rsc.nodes.lock().unwrap();
+        update_node(
+            &mut mutex,
+            addr.as_str(),
+            read_data.rssi,
+            now,
+            task.opts.max_nodes,
+        )
+    };
+    if online {
+        info!("[{}] node {} is online", FN_NAME, addr);
+        push_event(task, addr.as_str(), NODE_EVENT_ONLINE, now);
+    }
+    if dedup.is_duplicate(
+        addr.as_str(),
+        rx_data.payload.as_slice(),
+        now.timestamp_millis(),
+    ) {
+        warn!("[{}] drop duplicated data from {}", FN_NAME, addr);
+        Metrics::inc(&metrics.uldata_duplicated);
+        return None;
+    }
+    let filtered = match task.opts.min_rssi {
+        None => false,
+        Some(min_rssi) => read_data.rssi < min_rssi,
+    };
+    let mut encrypted = false;
+    let payload = match task.opts.keys.get(addr.as_str()) {
+        None => rx_data.payload,
+        Some(key) => {
+            match crypto::decrypt_uplink(key, rx_data.node_id, rx_data.payload.as_slice()) {
                 Err(e) => {
-                    warn!("[{}] get counter error: {}", FN_NAME, e);
-                    continue;
+                    warn!("[{}] decrypt data from {} error: {}", FN_NAME, addr, e);
+                    encrypted = true;
+                    rx_data.payload
                 }
-                Ok(data) => data,
-            };
-            let addr = format!("{:08x?}", rx_data.node_id);
-            let mut extension = Map::new();
-            extension.insert("rssi".to_string(), json!(read_data.rssi));
-            let uldata = NetUlData {
-                time: Utc::now(),
-                network_addr: addr.clone(),
-                data: rx_data.payload,
-                extension: Some(extension),
-            };
+                Ok(payload) => payload,
+            }
+        }
+    };
+    if !encrypted {
+        handle_ack(task, addr.as_str(), payload.as_slice());
+    }
+    let recv_time = strings::time_str(&now);
+    let mut extension = Map::new();
+    extension.insert("rssi".to_string(), json!(read_data.rssi));
+    extension.insert("freq".to_string(), json!(task.opts.freq));
+    extension.insert("counter".to_string(), json!(counter));
+    extension.insert("gatewayRecvTime".to_string(), json!(recv_time));
+    if encrypted {
+        extension.insert("encrypted".to_string(), json!(true));
+    }
+    if online && task.opts.publish_node_events {
+        extension.insert("event".to_string(), json!(NODE_EVENT_ONLINE));
+    }
+    // Undecrypted payloads are not decoded.
+    let decoded = match encrypted || task.opts.decoders.is_empty() {
+        false => Some(task.opts.decoders.decode(addr.as_str(), payload.as_slice())),
+        true => None,
+    };
+    if let Some(decoded) = decoded.as_ref() {
+        for (key, value) in decoded.iter() {
+            if !extension.contains_key(key) {
+                extension.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    let uldata = NetUlData {
+        time: now,
+        network_addr: addr.clone(),
+        data: payload,
+        extension: Some(extension),
+    };
+    {
+        let api_data = UlData {
+            time: strings::time_str(&uldata.time),
+            network_addr: addr.clone(),
+            data: hex::encode(&uldata.data),
+            extension: UlDataExt {
+                rssi: read_data.rssi,
+                encrypted,
+                freq: task.opts.freq,
+                counter,
+                gateway_recv_time: recv_time,
+                decoded,
+            },
+            filtered,
+        };
+        let mut mutex = task.queue_rsc.latest_uldata.lock().unwrap();
+        (*mutex).push_back(api_data);
+        if (*mutex).len() > MAX_DATA {
+            (*mutex).pop_front();
+        }
+    }
+    if filtered {
+        Metrics::inc(&metrics.uldata_filtered);
+        return None;
+    }
+    match publish_uldata(task, uldata) {
+        Err(e) => {
+            error!("[{}] send uldata message error: {}", FN_NAME, e);
+            Metrics::inc(&metrics.publish_errors);
+            return None;
+        }
+        Ok(false) => Metrics::inc(&metrics.uldata_buffered),
+        Ok(true) => Metrics::inc(&metrics.uldata_published),
+    }
+
+    Some(addr)
+}
+
+/// To update the status of the node. The least recently heard node will be removed if the map is
+/// full.
+///
+/// Returns `true` if the node was offline.
+fn update_node(
+    nodes: &mut HashMap<String, NodeStatus>,
+    addr: &str,
+    rssi: i16,
+    now: DateTime<Utc>,
+    max_nodes: usize,
+) -> bool {
+    if let Some(node) = nodes.get_mut(addr) {
+        node.last_seen = now;
+        node.last_rssi = rssi;
+        node.uplink_count += 1;
+        let offline = node.offline;
+        node.offline = false;
+        return offline;
+    }
+    if nodes.len() >= max_nodes {
+        let oldest = nodes
+            .iter()
+            .min_by_key(|(_, node)| node.last_seen)
+            .map(|(addr, _)| addr.clone());
+        if let Some(oldest) = oldest {
+            nodes.remove(oldest.as_str());
+        }
+    }
+    nodes.insert(
+        addr.to_string(),
+        NodeStatus {
+            last_seen: now,
+            last_rssi: rssi,
+            uplink_count: 1,
+            offline: false,
+        },
+    );
+    false
+}
+
+/// To record the event of the node.
+fn push_event(task: &LoraTask, addr: &str, event: &'static str, now: DateTime<Utc>) {
+    let mut mutex = task.queue_rsc.events.lock().unwrap();
+    (*mutex).push_back(NodeEvent {
+        time: strings::time_str(&now),
+        network_addr: addr.to_string(),
+        event,
+    });
+    if (*mutex).len() > MAX_DATA {
+        (*mutex).pop_front();
+    }
+}
+
+/// To create a timer task that marks nodes not heard in `offline_secs` as offline, and publishes
+/// offline events if `publish_node_events` is enabled.
+fn create_watchdog_loop(task: LoraTask, offline_secs: u64) -> JoinHandle<()> {
+    task::spawn(async move {
+        const FN_NAME: &'static str = "watchdog_loop";
+        let offline_time = TimeDelta::seconds(offline_secs as i64);
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(WATCHDOG_INTERVAL_MS)) => {}
+            }
+
+            let now = Utc::now();
+            let mut offline = vec![];
             {
-                let api_data = UlData {
-                    time: strings::time_str(&uldata.time),
+                let mut mutex = task.queue_rsc.nodes.lock().unwrap();
+                for (addr, node) in (*mutex).iter_mut() {
+                    if !node.offline && now - node.last_seen > offline_time {
+                        node.offline = true;
+                        offline.push(addr.clone());
+                    }
+                }
+            }
+            offline.sort();
+
+            for addr in offline {
+                warn!(
+                    "[{}] node {} is not heard in {} seconds",
+                    FN_NAME, addr, offline_secs
+                );
+                push_event(&task, addr.as_str(), NODE_EVENT_OFFLINE, now);
+                if !task.opts.publish_node_events {
+                    continue;
+                }
+                let mut extension = Map::new();
+                extension.insert("event".to_string(), json!(NODE_EVENT_OFFLINE));
+                let uldata = NetUlData {
+                    time: now,
                     network_addr: addr.clone(),
-                    data: hex::encode(&uldata.data),
-                    extension: UlDataExt {
-                        rssi: read_data.rssi,
-                    },
+                    data: vec![],
+                    extension: Some(extension),
                 };
-                let mut mutex = task.queue_rsc.latest_uldata.lock().unwrap();
-                (*mutex).push_back(api_data);
-                if (*mutex).len() > MAX_DATA {
-                    (*mutex).pop_front();
+                if let Err(e) = publish_uldata(&task, uldata) {
+                    error!("[{}] send event of {} error: {}", FN_NAME, addr, e);
+                    Metrics::inc(&task.queue_rsc.metrics.publish_errors);
                 }
             }
-            {
-                if let Err(e) = task.queue_rsc.mgr.lock().unwrap().send_uldata(&uldata) {
-                    error!("[{}] send uldata message error: {}", FN_NAME, e);
+        }
+    })
+}
+
+/// To send all queued downlink data of addresses that are last heard by this dongle without waiting
+/// for uplink data. At most `max_tx_per_window` data of each address will be sent each time.
+async fn flush_dldata(task: &LoraTask, port: &PortClient) {
+    let addrs: Vec<String> = {
+        let mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+        let addr_dongles = task.queue_rsc.addr_dongles.lock().unwrap();
+        (*mutex)
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .filter(|(addr, _)| match (*addr_dongles).get(addr.as_str()) {
+                None => task.opts.index == 0,
+                Some(index) => *index == task.opts.index,
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect()
+    };
+    for (i, addr) in addrs.iter().enumerate() {
+        if i > 0 {
+            time::sleep(Duration::from_millis(TX_INTERVAL_MS)).await;
+        }
+        send_window(task, port, addr.as_str()).await;
+    }
+}
+
+/// To send at most `max_tx_per_window` queued downlink data of the address.
+///
+/// Returns the number of transmitted data.
+async fn send_window(task: &LoraTask, port: &PortClient, addr: &str) -> usize {
+    let mut sent = 0;
+    while sent < task.opts.max_tx_per_window {
+        let data = match pop_dldata(task, addr) {
+            None => break,
+            Some(data) => data,
+        };
+        if sent > 0 {
+            time::sleep(Duration::from_millis(TX_INTERVAL_MS)).await;
+        }
+        if !send_dldata(task, port, data).await {
+            break;
+        }
+        sent += 1;
+    }
+    sent
+}
+
+impl DedupCache {
+    /// Create a cache with the window in milliseconds. `0` means no deduplication.
+    fn new(window_ms: u64) -> Self {
+        DedupCache {
+            window_ms: window_ms as i64,
+            frames: HashMap::new(),
+        }
+    }
+
+    /// To check if the payload is identical to the last frame of the address within the window.
+    /// Non-duplicated frames will be recorded and aged entries will be removed.
+    fn is_duplicate(&mut self, addr: &str, payload: &[u8], now_ms: i64) -> bool {
+        if self.window_ms <= 0 {
+            return false;
+        }
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let window_ms = self.window_ms;
+        self.frames
+            .retain(|_, (_, time)| now_ms - *time < window_ms);
+        if let Some((last_hash, _)) = self.frames.get(addr) {
+            if *last_hash == hash {
+                return true;
+            }
+        }
+        self.frames.insert(addr.to_string(), (hash, now_ms));
+        false
+    }
+}
+
+/// To calculate the sleep time (ms) from `now_ms` to the next multiple of `align_ms`.
+fn align_sleep_ms(now_ms: i64, align_ms: u64) -> u64 {
+    align_ms - (now_ms as u64 % align_ms)
+}
+
+/// To calculate the number of new frames from two RX data counters with wraparound.
+fn counter_delta(old: u16, new: u16) -> u16 {
+    new.wrapping_sub(old)
+}
+
+/// To parse AES keys of nodes. The network addresses will be converted to lowercase.
+fn parse_keys(
+    keys: &HashMap<String, String>,
+) -> Result<HashMap<String, [u8; crypto::KEY_LEN]>, IoError> {
+    let mut result = HashMap::new();
+    for (addr, key) in keys.iter() {
+        match crypto::parse_key(key.as_str()) {
+            Err(e) => {
+                let e = format!("key of {}: {}", addr, e);
+                return Err(IoError::new(ErrorKind::InvalidInput, e));
+            }
+            Ok(key) => result.insert(addr.to_lowercase(), key),
+        };
+    }
+    Ok(result)
+}
+
+/// To check radio values and payload limits because the serial commands replace invalid values
+/// with defaults.
+fn check_values(opts: &Options) -> Result<(), Box<dyn StdError>> {
+    IfroglabLoraBuilder::new(opts.dev_path.as_str())
+        .freq(opts.freq)
+        .power(opts.power)
+        .radio(opts.bw, opts.cr, opts.sf)
+        .validate()?;
+    let max_payload = opts.max_dl_payload_bytes;
+    if !(1..=MAX_DL_PAYLOAD).contains(&max_payload) {
+        return Err(Box::new(IoError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "maxDlPayloadBytes {} out of range 1~{}",
+                max_payload, MAX_DL_PAYLOAD
+            ),
+        )));
+    }
+    Ok(())
+}
+
+impl PortClient {
+    /// To get the RX data counter and the number of new RX frames since the last poll.
+    async fn poll_counter(&self) -> Result<(u16, u16), IoError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.request(PortCmd::PollCounter(resp_tx), resp_rx).await
+    }
+
+    /// To read pending RX frames.
+    async fn read_all(&self, max: usize) -> Result<Vec<ReadData>, IoError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.request(PortCmd::ReadAll(max, resp_tx), resp_rx).await
+    }
+
+    /// To get the cached chip values.
+    async fn chip_values(&self, max_age: Duration) -> Result<ChipValues, IoError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.request(PortCmd::ChipValues(max_age, resp_tx), resp_rx)
+            .await
+    }
+
+    /// To transmit one frame.
+    async fn transmit(&self, frame: Vec<u8>, freq: u32) -> Result<(), IoError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.request(PortCmd::Transmit(frame, freq, resp_tx), resp_rx)
+            .await
+    }
+
+    async fn request<T>(
+        &self,
+        cmd: PortCmd,
+        resp_rx: oneshot::Receiver<Result<T, IoError>>,
+    ) -> Result<T, IoError> {
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(IoError::new(ErrorKind::BrokenPipe, "port task closed"));
+        }
+        match resp_rx.await {
+            Err(_) => Err(IoError::new(ErrorKind::BrokenPipe, "port task closed")),
+            Ok(result) => result,
+        }
+    }
+}
+
+/// To connect to the USB dongle and set it to RX mode. This function retries until success.
+///
+/// Returns the port and the current RX data counter.
+async fn connect_port(task: &LoraTask) -> (Box<dyn LoraPort>, u16) {
+    const FN_NAME: &'static str = "connect_port";
+
+    loop {
+        time::sleep(Duration::from_millis(task.opts.poll_interval_ms)).await;
+        let (mut port, info, path) = match task.opts.sim.as_ref() {
+            None => match open_port(task).await {
+                Err(e) => {
+                    error!("[{}] create port error: {}", FN_NAME, e);
                     continue;
                 }
+                Ok((port, info, path)) => (Box::new(port) as Box<dyn LoraPort>, info, path),
+            },
+            Some(sim) => match open_sim_port(task, sim.clone()).await {
+                Err(e) => {
+                    error!("[{}] create simulated port error: {}", FN_NAME, e);
+                    continue;
+                }
+                Ok(result) => result,
+            },
+        };
+        match port.cmd02_get_chip_values().await {
+            Err(e) => {
+                error!("[{}] get chip values error: {}", FN_NAME, e);
+                continue;
             }
+            Ok(values) => info!(
+                "[{}] chip values: mode={}, freq={}, power={}, bw={}, cr={}, sf={}",
+                FN_NAME, values.mode, values.freq, values.power, values.bw, values.cr, values.sf
+            ),
+        }
+        let counter = match port.cmd07_read_data_counter().await {
+            Err(e) => {
+                error!("[{}] get counter error: {}", FN_NAME, e);
+                continue;
+            }
+            Ok(counter) => counter,
+        };
+        info!(
+            "[{}] connected to port {}, fw_ver={}, chip_id={}",
+            FN_NAME, path, info.fw_ver, info.chip_id
+        );
+        {
+            let mut mutex = task.queue_rsc.port_paths.lock().unwrap();
+            (*mutex).insert(task.opts.index, path);
+        }
+        return (port, counter);
+    }
+}
 
-            // Send one downlink data to the node if there are queued data to be send.
-            let mut data = {
-                let mut mutex = task.queue_rsc.queue_dldata.lock().unwrap();
-                match (*mutex).get_mut(addr.as_str()) {
-                    None => continue,
-                    Some(queue) => match queue.pop_front() {
-                        None => continue,
-                        Some(data) => data,
-                    },
-                }
+/// To reopen the broken port with [`LoraPort::reopen()`].
+///
+/// Returns the port and the current RX data counter, or `None` if the port should be connected
+/// again with [`connect_port()`].
+async fn reopen_port(port: Box<dyn LoraPort>) -> Option<(Box<dyn LoraPort>, u16)> {
+    const FN_NAME: &'static str = "reopen_port";
+
+    let mut port = match port.reopen().await {
+        Err((e, path)) => {
+            error!("[{}] reopen port {} error: {}", FN_NAME, path, e);
+            return None;
+        }
+        Ok(port) => port,
+    };
+    match port.cmd07_read_data_counter().await {
+        Err(e) => {
+            error!("[{}] get counter error: {}", FN_NAME, e);
+            None
+        }
+        Ok(counter) => Some((port, counter)),
+    }
+}
+
+/// To open the port of the device path, or detect the dongle if the device path is `auto`. Radio
+/// settings will be applied and the dongle will be in RX mode.
+///
+/// Returns the port, the chip information and the device path.
+async fn open_port(task: &LoraTask) -> Result<(IfroglabLora, ChipInfo, String), BuildError> {
+    let path = task.opts.dev_path.as_str();
+    let builder = port_builder(&task.opts);
+    if path != lora_usb::DEV_PATH_AUTO {
+        let (port, info) = builder.build().await?;
+        return Ok((port, info, path.to_string()));
+    }
+
+    let exclude: Vec<String> = {
+        let mut mutex = task.queue_rsc.port_paths.lock().unwrap();
+        (*mutex).remove(&task.opts.index);
+        (*mutex).values().cloned().collect()
+    };
+    let (port, path) = lora_usb::detect(
+        task.opts.usb_filter,
+        exclude.as_slice(),
+        task.opts.port_opts,
+    )
+    .await?;
+    let (port, info) = builder.setup(port).await?;
+    Ok((port, info, path))
+}
+
+/// To create the simulated dongle in RX mode with the settings of the task.
+///
+/// Returns the port, the chip information and the device path.
+async fn open_sim_port(
+    task: &LoraTask,
+    sim: SimOptions,
+) -> Result<(Box<dyn LoraPort>, ChipInfo, String), IoError> {
+    let opts = &task.opts;
+    let values = ChipValues {
+        mode: 3,
+        freq: opts.freq,
+        power: opts.power,
+        bw: opts.bw,
+        cr: opts.cr,
+        sf: opts.sf,
+    };
+    let mut port = SimLoraPort::new(opts.dev_path.as_str(), sim, values);
+    let info = port.cmd00_chip_info().await?;
+    Ok((Box::new(port), info, opts.dev_path.clone()))
+}
+
+/// To create the port builder with the settings of the task.
+fn port_builder(opts: &OptionsInner) -> IfroglabLoraBuilder {
+    IfroglabLoraBuilder::new(opts.dev_path.as_str())
+        .options(opts.port_opts)
+        .mode(3)
+        .freq(opts.freq)
+        .power(opts.power)
+        .radio(opts.bw, opts.cr, opts.sf)
+}
+
+/// To reset the dongle and apply settings again with [`LoraPort::recover()`].
+///
+/// Returns the current RX data counter.
+async fn reset_port(task: &LoraTask, port: &mut dyn LoraPort) -> Result<u16, IoError> {
+    const FN_NAME: &'static str = "reset_port";
+
+    let opts = &task.opts;
+    let values = port
+        .recover(opts.freq, opts.power, Some((opts.bw, opts.cr, opts.sf)))
+        .await?;
+    info!(
+        "[{}] chip values: mode={}, freq={}, power={}, bw={}, cr={}, sf={}",
+        FN_NAME, values.mode, values.freq, values.power, values.bw, values.cr, values.sf
+    );
+    match port.cmd07_read_data_counter().await {
+        Err(e) => Err(IoError::new(e.kind(), format!("get counter error: {}", e))),
+        Ok(counter) => Ok(counter),
+    }
+}
+
+/// To pop the first queuing downlink data of the address. The data stays queued if the TX budget
+/// of the node is exhausted.
+fn pop_dldata(task: &LoraTask, addr: &str) -> Option<DlData> {
+    let mut mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+    let queue = (*mutex).get_mut(addr)?;
+    if queue.is_empty() || !take_tx_budget(task, addr) {
+        return None;
+    }
+    queue.pop_front()
+}
+
+/// To take one TX token of the node. Broadcast data are not limited.
+///
+/// Returns `false` and counts the deferral if the budget is exhausted.
+fn take_tx_budget(task: &LoraTask, addr: &str) -> bool {
+    let per_minute = match task.opts.node_tx_per_minute {
+        None => return true,
+        Some(per_minute) => per_minute,
+    };
+    if addr == BROADCAST_ADDR {
+        return true;
+    }
+    let now_ms = Utc::now().timestamp_millis();
+    let mut mutex = task.queue_rsc.tx_budget.lock().unwrap();
+    let bucket = (*mutex)
+        .entry(addr.to_string())
+        .or_insert_with(|| TokenBucket::new(per_minute, now_ms));
+    if bucket.take(per_minute, now_ms) {
+        return true;
+    }
+    Metrics::inc(&task.queue_rsc.metrics.dldata_deferred);
+    false
+}
+
+/// To pop the first queuing downlink data of the next address after `last_addr` in round-robin.
+/// Only addresses that are last heard by this dongle (or not heard yet for the first dongle) are
+/// served. Broadcast data are excluded because they are sent after each uplink data. Addresses
+/// with exhausted TX budgets are skipped.
+fn pop_fair_dldata(task: &LoraTask, last_addr: &mut String) -> Option<DlData> {
+    let mut mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+    let mut addrs: Vec<&String> = {
+        let addr_dongles = task.queue_rsc.addr_dongles.lock().unwrap();
+        (*mutex)
+            .iter()
+            .filter(|(addr, queue)| !queue.is_empty() && addr.as_str() != BROADCAST_ADDR)
+            .filter(|(addr, _)| match (*addr_dongles).get(addr.as_str()) {
+                None => task.opts.index == 0,
+                Some(index) => *index == task.opts.index,
+            })
+            .map(|(addr, _)| addr)
+            .collect()
+    };
+    addrs.sort();
+    let start = addrs
+        .iter()
+        .position(|addr| addr.as_str() > last_addr.as_str())
+        .unwrap_or(0);
+    addrs.rotate_left(start);
+    let addr = addrs
+        .into_iter()
+        .find(|addr| take_tx_budget(task, addr.as_str()))?
+        .clone();
+    *last_addr = addr.clone();
+    (*mutex)
+        .get_mut(addr.as_str())
+        .and_then(|queue| queue.pop_front())
+}
+
+/// To send one downlink data to the node. The frame header is built from the network address of
+/// the data.
+///
+/// Returns `true` if the data is transmitted.
+async fn send_dldata(task: &LoraTask, port: &PortClient, mut data: DlData) -> bool {
+    const FN_NAME: &'static str = "send_dldata";
+    let metrics = task.queue_rsc.metrics.as_ref();
+
+    if let Some(expires_at) = data.expires_at {
+        if expires_at <= Utc::now() {
+            warn!("[{}] data {} expired", FN_NAME, data.data_id);
+            Metrics::inc(&metrics.dldata_expired);
+            let result = DlDataResult {
+                data_id: data.data_id.clone(),
+                status: DL_RESULT_EXPIRED,
+                message: Some(format!("expired at {}", strings::time_str(&expires_at))),
             };
-            let mut buff = [0u8; 16];
-            let data_len = data.data.len();
-            if data_len > 16 {
-                let result = DlDataResult {
-                    data_id: data.data_id.clone(),
-                    status: 1,
-                    message: Some(format!("exceed 16-byte hexadecimal")),
-                };
-                {
-                    if let Err(e) = task
-                        .queue_rsc
-                        .mgr
-                        .lock()
-                        .unwrap()
-                        .send_dldata_result(&result)
-                    {
-                        error!("[{}] send result message error: {}", FN_NAME, e);
-                        continue;
-                    }
-                }
-            } else if let Err(e) =
-                hex::decode_to_slice(data.data.as_str(), &mut buff[8..8 + data_len / 2])
-            {
+            if let Err(e) = send_result(task, &result) {
+                error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+            }
+            push_latest_dldata(task, data, result);
+            return false;
+        }
+    }
+
+    let start = Utc::now();
+    let key = match task.opts.keys.get(data.network_addr.as_str()) {
+        None => None,
+        Some(key) => match task.queue_rsc.dl_nonces.next(data.network_addr.as_str()) {
+            Err(e) => {
+                // Do not transmit with a nonce that may be reused after restarting.
                 error!(
-                    "[{}] decode hexadecimal data error: {}, data: {}",
-                    FN_NAME,
-                    e,
-                    data.data.as_str()
+                    "[{}] save nonce of {} error: {}",
+                    FN_NAME, data.network_addr, e
                 );
-                continue;
+                Metrics::inc(&metrics.tx_errors);
+                retry_or_report(task, data, &e);
+                return false;
             }
-            buff[0..4].clone_from_slice(&rx_data.node_id.to_be_bytes());
-            if let Err(e) = port
-                .cmd03_set_values(2, task.opts.freq, task.opts.power)
-                .await
-            {
-                error!("[{}] set TX mode error: {}", FN_NAME, e);
-                if let Err(e) = port
-                    .cmd03_set_values(3, task.opts.freq, task.opts.power)
-                    .await
-                {
-                    error!("[{}] set back RX mode error: {}", FN_NAME, e);
+            Ok(nonce) => Some((key, nonce)),
+        },
+    };
+    let frame = match build_frame(&data, key, task.opts.max_dl_payload_bytes) {
+        Err(e) => {
+            warn!(
+                "[{}] drop invalid data {}: {}, data: {}",
+                FN_NAME,
+                data.data_id,
+                e,
+                data.data.as_str()
+            );
+            let result = DlDataResult {
+                data_id: data.data_id.clone(),
+                status: DL_RESULT_INVALID,
+                message: Some(e),
+            };
+            if let Err(e) = send_result(task, &result) {
+                error!("[{}] send result message error: {}", FN_NAME, e);
+            }
+            push_latest_dldata(task, data, result);
+            return false;
+        }
+        Ok(frame) => frame,
+    };
+    let airtime_ms = airtime_ms(&task.opts, frame.len());
+    let tx_freq = next_tx_freq(task);
+    if let Err(e) = port.transmit(frame, tx_freq).await {
+        error!("[{}] transmit error: {}", FN_NAME, e);
+        Metrics::inc(&metrics.tx_errors);
+        retry_or_report(task, data, &e);
+        return false;
+    }
+    Metrics::inc(&metrics.dldata_sent);
+    // Broadcast data cannot be acknowledged by one node, and data without payload have nothing to
+    // be echoed.
+    let ack_tracker = match data.network_addr.as_str() == BROADCAST_ADDR || data.data.is_empty() {
+        false => task.queue_rsc.ack_tracker.as_ref(),
+        true => None,
+    };
+    let result = DlDataResult {
+        data_id: data.data_id.clone(),
+        status: match ack_tracker.is_some() {
+            false => DL_RESULT_SENT,
+            true => DL_RESULT_TRANSMITTED,
+        },
+        message: None,
+    };
+    if let Err(e) = send_result(task, &result) {
+        error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+    }
+    let now = Utc::now();
+    data.sent = strings::time_str(&now);
+    data.latency_ms = match DateTime::parse_from_rfc3339(data.publish.as_str()) {
+        Err(_) => None,
+        Ok(publish) => Some((now.timestamp_millis() - publish.timestamp_millis()).max(0) as u64),
+    };
+    data.airtime_ms = airtime_ms;
+    data.tx_freq = Some(tx_freq);
+    data.queued_ms = match DateTime::parse_from_rfc3339(data.publish.as_str()) {
+        Err(_) => None,
+        Ok(publish) => Some((start.timestamp_millis() - publish.timestamp_millis()).max(0) as u64),
+    };
+    if let Some(queued_ms) = data.queued_ms {
+        metrics.record_queued_ms(queued_ms);
+    }
+    if let Some(latency_ms) = data.latency_ms {
+        metrics
+            .dldata_latency_ms
+            .fetch_add(latency_ms, Ordering::Relaxed);
+        metrics
+            .dldata_latency_samples
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    metrics
+        .dldata_airtime_ms
+        .fetch_add(data.airtime_ms, Ordering::Relaxed);
+    match ack_tracker {
+        None => push_latest_dldata(task, data, result),
+        Some(ack_tracker) => {
+            let payload = hex::decode(data.data.as_str()).unwrap_or_default();
+            let mut mutex = ack_tracker.lock().unwrap();
+            (*mutex).track(data, payload.as_slice(), now);
+        }
+    }
+    true
+}
+
+/// To record the concluded downlink data with the result into the latest downlink data.
+fn push_latest_dldata(task: &LoraTask, mut data: DlData, result: DlDataResult) {
+    data.result = Some(result.status);
+    data.result_message = result.message;
+    let mut mutex = task.queue_rsc.latest_dldata.lock().unwrap();
+    (*mutex).push_back(data);
+    if (*mutex).len() > MAX_DATA {
+        (*mutex).pop_front();
+    }
+}
+
+/// To estimate the airtime (ms, rounded up) of the frame with the radio parameters. This uses the
+/// formula of the Semtech SX127x datasheet with 8 preamble symbols, explicit header and CRC.
+fn airtime_ms(opts: &OptionsInner, len: usize) -> u64 {
+    let bw_hz = match opts.bw {
+        1 => 125_000.0,
+        2 => 250_000.0,
+        _ => 500_000.0,
+    };
+    let sf = (opts.sf + 5) as f64;
+    let cr = opts.cr as f64;
+    let symbol_ms = 2f64.powf(sf) / bw_hz * 1000.0;
+    // Low data rate optimization is enabled when the symbol time exceeds 16 ms.
+    let de = match symbol_ms > 16.0 {
+        false => 0.0,
+        true => 1.0,
+    };
+    let preamble_ms = (8.0 + 4.25) * symbol_ms;
+    let payload_symbols = ((8.0 * len as f64 - 4.0 * sf + 28.0 + 16.0) / (4.0 * (sf - 2.0 * de)))
+        .ceil()
+        .max(0.0)
+        * (cr + 4.0)
+        + 8.0;
+    (preamble_ms + payload_symbols * symbol_ms).ceil() as u64
+}
+
+/// To get the frequency of the next TX frame from `freq_list` in round-robin, or `freq` if the list
+/// is empty.
+fn next_tx_freq(task: &LoraTask) -> u32 {
+    let freq_list = &task.opts.freq_list;
+    match freq_list.is_empty() {
+        false => freq_list[task.tx_channel.fetch_add(1, Ordering::Relaxed) % freq_list.len()],
+        true => task.opts.freq,
+    }
+}
+
+/// To switch to TX mode with `freq`, send the frame with command 0x05, and then switch back to RX
+/// mode with the RX frequency.
+async fn transmit(
+    port: &mut dyn LoraPort,
+    opts: &OptionsInner,
+    freq: u32,
+    frame: &[u8],
+) -> Result<(), IoError> {
+    const FN_NAME: &'static str = "transmit";
+
+    let result = match port.cmd03_set_values(2, freq, opts.power).await {
+        Err(e) => Err(IoError::new(e.kind(), format!("set TX mode error: {}", e))),
+        Ok(_) => port.cmd05_write_data(frame).await,
+    };
+    if let Err(e) = port.cmd03_set_values(3, opts.freq, opts.power).await {
+        error!("[{}] set back RX mode error: {}", FN_NAME, e);
+    }
+    result
+}
+
+/// To push the failed downlink data back to the front of its queue, or report the failure to the
+/// broker if there are no more retries.
+fn retry_or_report(task: &LoraTask, mut data: DlData, err: &IoError) {
+    const FN_NAME: &'static str = "retry_or_report";
+
+    data.attempts += 1;
+    if data.attempts <= task.opts.tx_retries {
+        requeue_dldata(task, data);
+        return;
+    }
+
+    let result = DlDataResult {
+        data_id: data.data_id.clone(),
+        status: DL_RESULT_TX_FAILED,
+        message: Some(format!(
+            "transmit error after {} attempts: {}",
+            data.attempts, err
+        )),
+    };
+    if let Err(e) = send_result(task, &result) {
+        error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+    }
+    push_latest_dldata(task, data, result);
+}
+
+/// To push the downlink data back to its queue ahead of data with the same or lower priority.
+fn requeue_dldata(task: &LoraTask, data: DlData) {
+    let mut mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+    if !(*mutex).contains_key(data.network_addr.as_str()) {
+        (*mutex).insert(data.network_addr.clone(), VecDeque::<DlData>::new());
+    }
+    let queue = (*mutex).get_mut(data.network_addr.as_str()).unwrap();
+    match queue.iter().position(|x| x.priority <= data.priority) {
+        None => queue.push_back(data),
+        Some(index) => queue.insert(index, data),
+    }
+}
+
+/// To report the downlink data that is acknowledged by the uplink data of the node.
+fn handle_ack(task: &LoraTask, addr: &str, payload: &[u8]) {
+    const FN_NAME: &'static str = "handle_ack";
+
+    let data = match task.queue_rsc.ack_tracker.as_ref() {
+        None => return,
+        Some(ack_tracker) => {
+            let mut mutex = ack_tracker.lock().unwrap();
+            match (*mutex).on_uplink(addr, payload) {
+                None => return,
+                Some(data) => data,
+            }
+        }
+    };
+    info!("[{}] data {} delivered to {}", FN_NAME, data.data_id, addr);
+    Metrics::inc(&task.queue_rsc.metrics.dldata_delivered);
+    let result = DlDataResult {
+        data_id: data.data_id.clone(),
+        status: DL_RESULT_SENT,
+        message: Some("delivered".to_string()),
+    };
+    if let Err(e) = send_result(task, &result) {
+        error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+    }
+    push_latest_dldata(task, data, result);
+}
+
+/// To create a timer task that transmits downlink data again or reports the delivery failure to the
+/// broker when there is no acknowledgment before the deadline.
+fn create_ack_loop(task: LoraTask) -> JoinHandle<()> {
+    task::spawn(async move {
+        const FN_NAME: &'static str = "ack_loop";
+        let ack_tracker = match task.queue_rsc.ack_tracker.as_ref() {
+            None => return,
+            Some(ack_tracker) => ack_tracker.clone(),
+        };
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(SWEEP_INTERVAL_MS)) => {}
+            }
+
+            let timeouts = {
+                let mut mutex = ack_tracker.lock().unwrap();
+                (*mutex).expire(Utc::now())
+            };
+            let mut retried = false;
+            for timeout in timeouts {
+                match timeout {
+                    AckTimeout::Retry(data) => {
+                        warn!(
+                            "[{}] no acknowledgment of data {}, retry {}",
+                            FN_NAME, data.data_id, data.ack_retries
+                        );
+                        requeue_dldata(&task, data);
+                        retried = true;
+                    }
+                    AckTimeout::Failed(data) => {
+                        warn!("[{}] data {} undelivered", FN_NAME, data.data_id);
+                        Metrics::inc(&task.queue_rsc.metrics.dldata_undelivered);
+                        let result = DlDataResult {
+                            data_id: data.data_id.clone(),
+                            status: DL_RESULT_UNDELIVERED,
+                            message: Some(format!(
+                                "no acknowledgment after {} retries",
+                                data.ack_retries
+                            )),
+                        };
+                        if let Err(e) = send_result(&task, &result) {
+                            error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                        }
+                        push_latest_dldata(&task, data, result);
+                    }
                 }
-                continue;
             }
-            if let Err(e) = port.cmd05_write_data(&buff[..8 + data_len / 2]).await {
-                error!("[{}] send cmd05 error: {}", FN_NAME, e);
-                if let Err(e) = port
-                    .cmd03_set_values(3, task.opts.freq, task.opts.power)
-                    .await
-                {
-                    error!("[{}] set back RX mode error: {}", FN_NAME, e);
+            if retried {
+                if let Some(notify) = task.queue_rsc.tx_notify.as_ref() {
+                    notify.notify_one();
                 }
-                continue;
             }
-            if let Err(e) = port
-                .cmd03_set_values(3, task.opts.freq, task.opts.power)
-                .await
-            {
-                error!("[{}] set back RX mode error: {}", FN_NAME, e);
+        }
+    })
+}
+
+/// To create a timer task that sends buffered uplink data and downlink data results in order. The
+/// interval is doubled (at most `RESULT_RETRY_MAX_MS`) after each failure and reset after success.
+fn create_result_loop(task: LoraTask) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut interval_ms = RESULT_RETRY_MIN_MS;
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(interval_ms)) => {}
+            }
+
+            let flushed = flush_uldata(&task);
+            let flushed = {
+                let mgr = task.queue_rsc.mgr.lock().unwrap();
+                task.queue_rsc
+                    .result_buffer
+                    .flush(&mgr, task.queue_rsc.metrics.as_ref())
+                    && flushed
+            };
+            interval_ms = match flushed {
+                false => (interval_ms * 2).min(RESULT_RETRY_MAX_MS),
+                true => RESULT_RETRY_MIN_MS,
+            };
+        }
+    })
+}
+
+/// To create a timer task that removes expired downlink data from the queue and reports the
+/// expiry to the broker.
+fn create_sweep_loop(task: LoraTask) -> JoinHandle<()> {
+    task::spawn(async move {
+        const FN_NAME: &'static str = "sweep_loop";
+        let ttl_ms = task
+            .opts
+            .dldata_ttl_secs
+            .saturating_mul(1000)
+            .min(i64::MAX as u64) as i64;
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(SWEEP_INTERVAL_MS)) => {}
             }
-            data.sent = strings::time_str(&Utc::now());
+
+            let now = Utc::now().timestamp_millis();
+            let mut expired = vec![];
             {
-                let mut mutex = task.queue_rsc.latest_dldata.lock().unwrap();
-                (*mutex).push_back(data);
-                if (*mutex).len() > MAX_DATA {
-                    (*mutex).pop_front();
+                let mut mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+                for queue in (*mutex).values_mut() {
+                    queue.retain(|data| {
+                        match DateTime::parse_from_rfc3339(data.publish.as_str()) {
+                            Err(_) => true,
+                            Ok(publish) => match now - publish.timestamp_millis() > ttl_ms {
+                                false => true,
+                                true => {
+                                    expired.push(data.clone());
+                                    false
+                                }
+                            },
+                        }
+                    });
+                }
+            }
+
+            for data in expired {
+                warn!("[{}] data {} expired", FN_NAME, data.data_id);
+                Metrics::inc(&task.queue_rsc.metrics.dldata_expired);
+                let result = DlDataResult {
+                    data_id: data.data_id.clone(),
+                    status: DL_RESULT_EXPIRED,
+                    message: Some(format!(
+                        "expired after {} seconds",
+                        task.opts.dldata_ttl_secs
+                    )),
+                };
+                if let Err(e) = send_result(&task, &result) {
+                    error!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
                 }
+                push_latest_dldata(&task, data, result);
             }
         }
     })
 }
 
+/// To create a timer task to save the downlink queue into the file when it is changed.
+fn create_save_loop(task: LoraTask, path: String) -> JoinHandle<()> {
+    task::spawn(async move {
+        const FN_NAME: &'static str = "save_loop";
+        let mut last_content = String::new();
+        loop {
+            tokio::select! {
+                _ = task.cancel.cancelled() => break,
+                _ = time::sleep(Duration::from_millis(SAVE_INTERVAL_MS)) => {}
+            }
+
+            let content = {
+                let mutex = task.queue_rsc.queue_dldata.lock().unwrap();
+                queue_file::serialize(&mutex)
+            };
+            let content = match content {
+                Err(e) => {
+                    error!("[{}] serialize queue error: {}", FN_NAME, e);
+                    continue;
+                }
+                Ok(content) => match content == last_content {
+                    false => content,
+                    true => continue,
+                },
+            };
+            match queue_file::save(path.as_str(), content.as_str()) {
+                Err(e) => error!("[{}] save {} error: {}", FN_NAME, path, e),
+                Ok(_) => last_content = content,
+            }
+        }
+    })
+}
+
+/// To build the TX frame with the node address, the reserved bytes, and the payload. The payload
+/// will be encrypted with the nonce if the node has a key.
+fn build_frame(
+    data: &DlData,
+    key: Option<(&[u8; crypto::KEY_LEN], u16)>,
+    max_payload: usize,
+) -> Result<Vec<u8>, String> {
+    let addr = data.network_addr.as_str();
+    if addr.len() != 8 {
+        return Err(format!("invalid network address {}", addr));
+    }
+    let node_id = match u32::from_str_radix(addr, 16) {
+        Err(e) => return Err(format!("invalid network address {}: {}", addr, e)),
+        Ok(node_id) => node_id,
+    };
+    let mut payload = decode_payload(data.data.as_str(), max_payload)?;
+    if let Some((key, nonce)) = key {
+        if payload.len() + crypto::NONCE_LEN > max_payload {
+            return Err(format!(
+                "exceed {}-byte payload for encryption",
+                max_payload.saturating_sub(crypto::NONCE_LEN)
+            ));
+        }
+        payload = crypto::encrypt_downlink(key, node_id, nonce, payload.as_slice());
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&node_id.to_be_bytes());
+    frame.extend_from_slice(&[0u8; 4]);
+    frame.extend_from_slice(payload.as_slice());
+    Ok(frame)
+}
+
+/// To decode the hexadecimal payload of the downlink data.
+fn decode_payload(data: &str, max_payload: usize) -> Result<Vec<u8>, String> {
+    if data.len() & 1 != 0 {
+        return Err(format!("odd hexadecimal length {}", data.len()));
+    } else if data.len() / 2 > max_payload {
+        return Err(format!("exceed {}-byte payload", max_payload));
+    }
+    match hex::decode(data) {
+        Err(e) => Err(format!("invalid hexadecimal: {}", e)),
+        Ok(payload) => Ok(payload),
+    }
+}
+
+/// To send the downlink data result to the broker.
+fn send_result(task: &LoraTask, result: &DlDataResult) -> Result<(), Box<dyn StdError>> {
+    let metrics = task.queue_rsc.metrics.as_ref();
+    let result = {
+        let mgr = task.queue_rsc.mgr.lock().unwrap();
+        task.queue_rsc.result_buffer.send(&mgr, result, metrics)
+    };
+    if result.is_err() {
+        Metrics::inc(&task.queue_rsc.metrics.publish_errors);
+    }
+    result
+}
+
 fn parse_rx_data(raw: &[u8]) -> Result<RxData, IoError> {
-    if raw.len() < 8 {
+    if raw.len() < FRAME_HEADER_LEN {
         return Err(IoError::from(ErrorKind::InvalidData));
     }
 
@@ -279,6 +1998,6 @@ fn parse_rx_data(raw: &[u8]) -> Result<RxData, IoError> {
     dst.clone_from_slice(&raw[0..4]);
     Ok(RxData {
         node_id: u32::from_be_bytes(dst),
-        payload: raw[8..].to_vec(),
+        payload: raw[FRAME_HEADER_LEN..].to_vec(),
     })
 }
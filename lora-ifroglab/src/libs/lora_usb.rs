@@ -1,21 +1,125 @@
 //! iFrogLab USB dongle serial port operations.
 
 use std::{
+    error::Error as StdError,
+    fmt,
     io::{Error as IoError, ErrorKind},
     time::Duration,
 };
 
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use log::trace;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    time,
+    time::{self, Instant},
+};
+use tokio_serial::{
+    DataBits, FlowControl, Parity, SerialPort, SerialPortBuilderExt, SerialPortType, SerialStream,
+    StopBits,
 };
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
 
 /// Provides functions to control iFrogLab LoRa USB dongle.
 pub struct IfroglabLora {
     port: SerialStream,
-    buff: [u8; 24],
+    /// The device path to reopen the port.
+    path: String,
+    /// Received bytes that are not consumed.
+    buff: Vec<u8>,
+    /// Bytes of the last ACK frame in `buff` that will be removed by the next read.
+    consumed: usize,
+    /// Invalid CRC counts since the last [`LoraPort::take_crc_errors()`].
+    crc_errors: u64,
+    /// The last mode, frequency and power that are applied with command 0x03.
+    values: Option<(u8, u32, u8)>,
+    /// The last chip values and the update time. Values are read with command 0x02 and derived
+    /// from commands 0x03 and 0x04.
+    chip_values: Option<(ChipValues, Instant)>,
+    opts: IfroglabLoraOptions,
+}
+
+/// Options of the ACK handling of [`IfroglabLora`].
+#[derive(Clone, Copy)]
+pub struct IfroglabLoraOptions {
+    /// The time to wait for the ACK after writing one command in milliseconds.
+    pub ack_sleep_ms: u64,
+    /// The time to receive one ACK frame and to retry one command before reporting the error in
+    /// milliseconds.
+    pub timeout_ms: u64,
+    /// Maximum retry times of one command. `None` means retrying until timeout.
+    pub max_retries: Option<usize>,
+    /// The baud rate of the serial port.
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    /// The read/write timeout of the serial port in milliseconds.
+    pub serial_timeout_ms: u64,
+    pub flow_control: FlowControl,
+    /// To assert (`true`) or deassert (`false`) DTR after opening the port. `None` to keep the
+    /// driver default.
+    pub dtr: Option<bool>,
+    /// To assert (`true`) or deassert (`false`) RTS after opening the port. `None` to keep the
+    /// driver default. This cannot be set with the hardware flow control.
+    pub rts: Option<bool>,
+}
+
+/// The USB VID/PID filter to detect dongles. `None` matches all values.
+#[derive(Clone, Copy, Default)]
+pub struct UsbFilter {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+}
+
+/// The builder to open the dongle port and apply settings with validation.
+///
+/// ```ignore
+/// let (port, info) = IfroglabLoraBuilder::new("/dev/ttyACM0")
+///     .freq(91500)
+///     .power(15)
+///     .radio(3, 1, 4)
+///     .build()
+///     .await?;
+/// ```
+pub struct IfroglabLoraBuilder {
+    path: String,
+    opts: IfroglabLoraOptions,
+    mode: u8,
+    freq: u32,
+    power: u8,
+    radio: Option<(u8, u8, u8)>,
+}
+
+/// Errors of [`IfroglabLoraBuilder`].
+#[derive(Debug)]
+pub enum BuildError {
+    /// The value is out of the range `min`~`max`.
+    OutOfRange {
+        name: &'static str,
+        value: u32,
+        min: u32,
+        max: u32,
+    },
+    /// Errors from the serial port or the dongle.
+    Io(IoError),
+}
+
+/// The retry state of one command.
+struct Retry {
+    start: DateTime<Utc>,
+    retries: usize,
+    timeout_ms: i64,
+    max_retries: Option<usize>,
+}
+
+/// The result of [`parse_frame()`].
+enum Frame {
+    /// More bytes are required.
+    Incomplete,
+    /// One complete frame with the frame size.
+    Complete(usize),
+    /// The frame with the frame size has invalid CRC.
+    InvalidCrc(usize),
 }
 
 /// Chip information from command 0x00.
@@ -26,6 +130,7 @@ pub struct ChipInfo {
 }
 
 /// Current chip values.
+#[derive(Clone)]
 pub struct ChipValues {
     /// Operation mode.
     /// - 0: sleep
@@ -64,37 +169,416 @@ pub struct ReadData {
     pub rssi: i16,
 }
 
-const TIMEOUT_MS: i64 = 1000;
-const ACK_SLEEP_MS: u64 = 20;
+/// Operations of the LoRa USB dongle. This abstracts the serial port from LoRa tasks.
+#[async_trait]
+pub trait LoraPort: Send {
+    /// To get invalid CRC counts of ACK frames and reset the counter.
+    fn take_crc_errors(&mut self) -> u64;
+
+    /// To get the chip information.
+    async fn cmd00_chip_info(&mut self) -> Result<ChipInfo, IoError>;
+
+    /// To check the port health with command 0x00 with a short timeout and no retries.
+    async fn ping(&mut self) -> Result<ChipInfo, IoError>;
+
+    /// To reset the chip.
+    async fn cmd01_reset(&mut self) -> Result<(), IoError>;
+
+    /// To get current chip values.
+    async fn cmd02_get_chip_values(&mut self) -> Result<ChipValues, IoError>;
+
+    /// To get the cached chip values. Command 0x02 will be used only if the cache is older than
+    /// `max_age`.
+    async fn chip_values_cached(&mut self, max_age: Duration) -> Result<ChipValues, IoError>;
+
+    /// To set the operation mode, the frequency and the power.
+    async fn cmd03_set_values(&mut self, mode: u8, freq: u32, power: u8) -> Result<(), IoError>;
+
+    /// To set the bandwidth, the code rate and the spreading factor.
+    async fn cmd04_set_values(&mut self, bw: u8, cr: u8, sf: u8) -> Result<(), IoError>;
+
+    /// To write one TX frame.
+    async fn cmd05_write_data(&mut self, data: &[u8]) -> Result<(), IoError>;
+
+    /// To read one RX frame. `None` means no data.
+    async fn cmd06_read_data(&mut self) -> Result<Option<ReadData>, IoError>;
+
+    /// To read pending RX frames with command 0x06 until no data or `max` frames are read.
+    ///
+    /// Frames that have been read will be returned if the following command fails. The error is
+    /// returned only if no frame is read.
+    async fn cmd06_read_all(&mut self, max: usize) -> Result<Vec<ReadData>, IoError> {
+        let mut frames = vec![];
+        while frames.len() < max {
+            match self.cmd06_read_data().await {
+                Err(e) => match frames.len() {
+                    0 => return Err(e),
+                    _ => break,
+                },
+                Ok(None) => break,
+                Ok(Some(data)) => frames.push(data),
+            }
+        }
+        Ok(frames)
+    }
+
+    /// To read the RX data counter.
+    async fn cmd07_read_data_counter(&mut self) -> Result<u16, IoError>;
+
+    /// To close the port and open it again. See [`IfroglabLora::reopen()`].
+    async fn reopen(self: Box<Self>) -> Result<Box<dyn LoraPort>, (IoError, String)>;
+
+    /// To recover the chip from a bad state. The chip will be reset with command 0x01, then the
+    /// frequency, the power and the radio values (bw, cr, sf) will be applied again in RX mode.
+    ///
+    /// Returns the chip values that are verified with command 0x02.
+    async fn recover(
+        &mut self,
+        freq: u32,
+        power: u8,
+        radio: Option<(u8, u8, u8)>,
+    ) -> Result<ChipValues, IoError> {
+        if let Err(e) = self.cmd01_reset().await {
+            return Err(IoError::new(e.kind(), format!("reset error: {}", e)));
+        }
+        time::sleep(Duration::from_millis(RESET_WAIT_MS)).await;
+        if let Some((bw, cr, sf)) = radio {
+            if let Err(e) = self.cmd04_set_values(bw, cr, sf).await {
+                return Err(IoError::new(e.kind(), format!("set bw/cr/sf error: {}", e)));
+            }
+        }
+        if let Err(e) = self.cmd03_set_values(3, freq, power).await {
+            return Err(IoError::new(e.kind(), format!("set RX mode error: {}", e)));
+        }
+        let values = match self.cmd02_get_chip_values().await {
+            Err(e) => {
+                return Err(IoError::new(
+                    e.kind(),
+                    format!("get chip values error: {}", e),
+                ))
+            }
+            Ok(values) => values,
+        };
+        let radio_ok = match radio {
+            None => true,
+            Some(radio) => radio == (values.bw, values.cr, values.sf),
+        };
+        if values.mode != 3 || values.freq != freq || values.power != power || !radio_ok {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unexpected chip values: mode={}, freq={}, power={}, bw={}, cr={}, sf={}",
+                    values.mode, values.freq, values.power, values.bw, values.cr, values.sf
+                ),
+            ));
+        }
+        Ok(values)
+    }
+}
+
+/// The maximum data size of one TX/RX frame.
+pub const MAX_FRAME_LEN: usize = 16;
+
+/// Baud rates that are supported by the serial port.
+pub const SUPPORTED_BAUD_RATES: [u32; 9] =
+    [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400];
+
+/// The device path to detect the dongle automatically with [`detect()`].
+pub const DEV_PATH_AUTO: &'static str = "auto";
+
+/// The read size of one serial port read.
+const READ_CHUNK_LEN: usize = 64;
+/// The time to wait for the chip after reset.
+const RESET_WAIT_MS: u64 = 1000;
+/// The time to wait for the system to release the device before reopening the port.
+const REOPEN_WAIT_MS: u64 = 500;
+/// The timeout of the health check command.
+const PING_TIMEOUT_MS: u64 = 500;
+/// The timeout of probing one serial port when detecting dongles.
+const PROBE_TIMEOUT_MS: u64 = 1000;
+
+pub const DEF_TIMEOUT_MS: u64 = 1000;
+pub const DEF_ACK_SLEEP_MS: u64 = 20;
+pub const DEF_BAUD_RATE: u32 = 115200;
+pub const DEF_SERIAL_TIMEOUT_MS: u64 = 2000;
+
+impl Default for IfroglabLoraOptions {
+    fn default() -> Self {
+        IfroglabLoraOptions {
+            ack_sleep_ms: DEF_ACK_SLEEP_MS,
+            timeout_ms: DEF_TIMEOUT_MS,
+            max_retries: None,
+            baud_rate: DEF_BAUD_RATE,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            serial_timeout_ms: DEF_SERIAL_TIMEOUT_MS,
+            flow_control: FlowControl::None,
+            dtr: None,
+            rts: None,
+        }
+    }
+}
 
 impl IfroglabLora {
-    /// Create a port stream for the USB dongle device.
+    /// Create a port stream for the USB dongle device with default options.
     pub fn new(path: &str) -> Result<Self, IoError> {
-        let port = tokio_serial::new(path, 115200)
-            .timeout(Duration::from_secs(2))
+        IfroglabLora::with_options(path, IfroglabLoraOptions::default())
+    }
+
+    /// Create a port stream for the USB dongle device with the specified options.
+    pub fn with_options(path: &str, opts: IfroglabLoraOptions) -> Result<Self, IoError> {
+        if !SUPPORTED_BAUD_RATES.contains(&opts.baud_rate) {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("unsupported baud rate {}", opts.baud_rate),
+            ));
+        }
+        if opts.flow_control == FlowControl::Hardware && opts.rts.is_some() {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "RTS cannot be set with the hardware flow control",
+            ));
+        }
+        let mut port = tokio_serial::new(path, opts.baud_rate)
+            .data_bits(opts.data_bits)
+            .parity(opts.parity)
+            .stop_bits(opts.stop_bits)
+            .flow_control(opts.flow_control)
+            .timeout(Duration::from_millis(opts.serial_timeout_ms))
             .open_native_async()?;
+        if let Some(dtr) = opts.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = opts.rts {
+            port.write_request_to_send(rts)?;
+        }
 
         Ok(IfroglabLora {
             port,
-            buff: [0u8; 24],
+            path: path.to_string(),
+            buff: Vec::with_capacity(READ_CHUNK_LEN),
+            consumed: 0,
+            crc_errors: 0,
+            values: None,
+            chip_values: None,
+            opts,
         })
     }
 
-    pub async fn cmd00_chip_info(&mut self) -> Result<ChipInfo, IoError> {
-        let cmd: [u8; 4] = [0x80, 0, 0, 0x80];
-        let data;
-        let start = Utc::now();
+    /// To close the broken port and open it again with the same options. The last mode, frequency
+    /// and power will be applied again.
+    ///
+    /// The port is dropped before reopening because some platforms keep the device locked while
+    /// the handle is alive. Returns the error with the device path for failures.
+    pub async fn reopen(self) -> Result<Self, (IoError, String)> {
+        let IfroglabLora {
+            port,
+            path,
+            values,
+            opts,
+            ..
+        } = self;
+        drop(port);
+        time::sleep(Duration::from_millis(REOPEN_WAIT_MS)).await;
+
+        let mut port = match IfroglabLora::with_options(path.as_str(), opts) {
+            Err(e) => return Err((e, path)),
+            Ok(port) => port,
+        };
+        if let Some((mode, freq, power)) = values {
+            if let Err(e) = port.cmd03_set_values(mode, freq, power).await {
+                return Err((e, path));
+            }
+        }
+        Ok(port)
+    }
+
+    /// To write the command frame and read the ACK with retries.
+    ///
+    /// - `expect_len`: the expected ACK data length.
+    /// - `expect_ack55`: the ACK data should be one byte `0x55`.
+    ///
+    /// Returns the ACK data from BYTE-4.
+    async fn transact(
+        &mut self,
+        frame: &[u8],
+        expect_len: Option<usize>,
+        expect_ack55: bool,
+    ) -> Result<&[u8], IoError> {
+        let code = match frame[0] {
+            0x80 => 0x00,
+            _ => frame[1],
+        };
+        let mut retry = self.retry();
         loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
+            self.write_cmd(code, frame, retry.attempt()).await?;
+            match self.read_ack().await {
+                Err(e) => retry.check(e)?,
+                Ok(_) => break,
+            }
+        }
+
+        let data = &self.buff[3..self.consumed - 1];
+        if let Some(len) = expect_len {
+            if data.len() != len {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("cmd-{:02x} should with {} bytes", code, len),
+                ));
+            }
+        }
+        if expect_ack55 {
+            if data.len() != 1 {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("cmd-{:02x} should with 1 byte", code),
+                ));
+            } else if data[0] != 0x55 {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("cmd-{:02x} not response 0x55: 0x{:02x?}", code, data[0]),
+                ));
+            }
+        }
+        Ok(data)
+    }
+
+    /// To write one command. `attempt` is the attempt number of the command from 1.
+    ///
+    /// The write is bounded by the command timeout. Buffered bytes will be cleared for timeout
+    /// because the port state is unknown.
+    async fn write_cmd(&mut self, code: u8, cmd: &[u8], attempt: usize) -> Result<(), IoError> {
+        const FN_NAME: &'static str = "IfroglabLora::write_cmd";
+
+        trace!(
+            "[{}] cmd-{:02x} attempt {} write {}",
+            FN_NAME,
+            code,
+            attempt,
+            hex::encode(cmd)
+        );
+        let timeout = Duration::from_millis(self.opts.timeout_ms);
+        match time::timeout(timeout, self.port.write_all(cmd)).await {
+            Err(_) => {
+                self.buff.clear();
+                self.consumed = 0;
+                Err(IoError::new(
+                    ErrorKind::TimedOut,
+                    format!("cmd-{:02x} write timeout", code),
+                ))
+            }
+            Ok(Err(e)) => Err(e),
+            Ok(Ok(_)) => Ok(()),
+        }
+    }
+
+    /// To start the retry state of one command.
+    fn retry(&self) -> Retry {
+        Retry {
+            start: Utc::now(),
+            retries: 0,
+            timeout_ms: self.opts.timeout_ms as i64,
+            max_retries: self.opts.max_retries,
+        }
+    }
+
+    /// Read ACK for the command from BYTE-4 (skip the first 3 bytes) with `len` size.
+    ///
+    /// The port will be read until one whole frame is parsed by [`parse_frame()`] or timeout. The
+    /// serial port may deliver partial frames or several frames in one read, so surplus bytes
+    /// after the frame will be kept for the next read. The buffer will be cleared for errors to
+    /// synchronize with the next frame.
+    async fn read_ack(&mut self) -> Result<&[u8], IoError> {
+        const FN_NAME: &'static str = "IfroglabLora::read_ack";
+
+        time::sleep(Duration::from_millis(self.opts.ack_sleep_ms)).await;
+
+        // Remove the last frame and keep surplus bytes.
+        self.buff.drain(..self.consumed);
+        self.consumed = 0;
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(self.opts.timeout_ms);
+        let mut chunk = [0u8; READ_CHUNK_LEN];
+        let size = loop {
+            match parse_frame(self.buff.as_slice()) {
+                Frame::Complete(size) => break size,
+                Frame::InvalidCrc(size) => {
+                    trace!(
+                        "[{}] read {} after {:?}, invalid crc {:02x}",
+                        FN_NAME,
+                        hex::encode(&self.buff[..size]),
+                        start.elapsed(),
+                        crc(&self.buff[..size - 1])
+                    );
+                    self.crc_errors += 1;
+                    let err = IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid CRC: {:?}, size: {}", self.buff, self.buff.len()),
+                    );
+                    self.buff.clear();
+                    return Err(err);
+                }
+                Frame::Incomplete => (),
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let result = time::timeout(timeout, self.port.read(&mut chunk)).await;
+            let err = match result {
+                Err(_) => IoError::new(
+                    ErrorKind::TimedOut,
+                    format!("only receive {} bytes", self.buff.len()),
+                ),
+                Ok(Err(e)) => e,
+                Ok(Ok(0)) => IoError::from(ErrorKind::UnexpectedEof),
+                Ok(Ok(size)) => {
+                    self.buff.extend_from_slice(&chunk[..size]);
                     continue;
                 }
-                Ok(data) => data,
             };
-            break;
+            trace!(
+                "[{}] error after {:?}: {}, read {}",
+                FN_NAME,
+                start.elapsed(),
+                err,
+                hex::encode(self.buff.as_slice())
+            );
+            self.buff.clear();
+            return Err(err);
+        };
+        trace!(
+            "[{}] read {} after {:?}, surplus {} bytes",
+            FN_NAME,
+            hex::encode(&self.buff[..size]),
+            start.elapsed(),
+            self.buff.len() - size
+        );
+        self.consumed = size;
+
+        if self.buff[1] == 0xff {
+            return Err(IoError::new(
+                ErrorKind::Other,
+                format!("{:02x?}", self.buff[3]),
+            ));
         }
+
+        Ok(&self.buff[3..size - 1])
+    }
+}
+
+#[async_trait]
+impl LoraPort for IfroglabLora {
+    /// To get invalid CRC counts of ACK frames and reset the counter.
+    fn take_crc_errors(&mut self) -> u64 {
+        let count = self.crc_errors;
+        self.crc_errors = 0;
+        count
+    }
+
+    async fn cmd00_chip_info(&mut self) -> Result<ChipInfo, IoError> {
+        let cmd: [u8; 4] = [0x80, 0, 0, 0x80];
+        let data = self.transact(&cmd, None, false).await?;
         if data.len() < 2 {
             return Err(IoError::new(
                 ErrorKind::InvalidData,
@@ -116,73 +600,51 @@ impl IfroglabLora {
         })
     }
 
-    pub async fn cmd01_reset(&mut self) -> Result<(), IoError> {
-        let cmd: [u8; 4] = [0xC1, 0x01, 0, 0xC0];
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
-        }
-        if data.len() != 1 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "cmd-01 should with 1 byte",
-            ));
-        } else if data[0] != 0x55 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("cmd-01 not response 0x55: 0x{:02x?}", data[0]),
-            ));
-        }
+    async fn ping(&mut self) -> Result<ChipInfo, IoError> {
+        let opts = self.opts;
+        self.opts.timeout_ms = PING_TIMEOUT_MS.min(opts.timeout_ms);
+        self.opts.max_retries = Some(0);
+        let result = self.cmd00_chip_info().await;
+        self.opts = opts;
+        result
+    }
 
+    async fn cmd01_reset(&mut self) -> Result<(), IoError> {
+        let cmd: [u8; 4] = [0xC1, 0x01, 0, 0xC0];
+        self.chip_values = None;
+        self.transact(&cmd, None, true).await?;
         Ok(())
     }
 
-    pub async fn cmd02_get_chip_values(&mut self) -> Result<ChipValues, IoError> {
+    async fn cmd02_get_chip_values(&mut self) -> Result<ChipValues, IoError> {
         let cmd: [u8; 4] = [0xC1, 0x02, 0, 0xC3];
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
-        }
-        if data.len() != 8 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "cmd-02 should with 8 bytes",
-            ));
-        }
-
+        let data = self.transact(&cmd, Some(8), false).await?;
         let mut dst = [0u8; 4];
         (&mut dst[1..]).clone_from_slice(&data[1..4]);
         let freq = u32::from_be_bytes(dst);
 
-        Ok(ChipValues {
+        let values = ChipValues {
             mode: data[0],
             freq,
             power: data[4],
             bw: data[5],
             cr: data[6],
             sf: data[7],
-        })
+        };
+        self.chip_values = Some((values.clone(), Instant::now()));
+        Ok(values)
     }
 
-    pub async fn cmd03_set_values(
+    async fn chip_values_cached(&mut self, max_age: Duration) -> Result<ChipValues, IoError> {
+        if let Some((values, time)) = self.chip_values.as_ref() {
+            if time.elapsed() <= max_age {
+                return Ok(values.clone());
+            }
+        }
+        self.cmd02_get_chip_values().await
+    }
+
+    async fn cmd03_set_values(
         &mut self,
         mut mode: u8,
         mut freq: u32,
@@ -203,35 +665,18 @@ impl IfroglabLora {
         cmd[5] = ((freq >> 8) & 0xff) as u8;
         cmd[6] = (freq & 0xff) as u8;
         cmd[8] = crc(&cmd);
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
+        self.transact(&cmd, None, true).await?;
+        self.values = Some((mode, freq, power));
+        if let Some((values, time)) = self.chip_values.as_mut() {
+            values.mode = mode;
+            values.freq = freq;
+            values.power = power;
+            *time = Instant::now();
         }
-        if data.len() != 1 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "cmd-03 should with 1 byte",
-            ));
-        } else if data[0] != 0x55 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("cmd-03 not response 0x55: 0x{:02x?}", data[0]),
-            ));
-        }
-
         Ok(())
     }
 
-    pub async fn cmd04_set_values(
+    async fn cmd04_set_values(
         &mut self,
         mut bw: u8,
         mut cr: u8,
@@ -249,36 +694,18 @@ impl IfroglabLora {
 
         let mut cmd: [u8; 7] = [0xC1, 0x04, 0x03, bw, cr, sf, 0];
         cmd[6] = crc(&cmd);
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
-        }
-        if data.len() != 1 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "cmd-04 should with 1 byte",
-            ));
-        } else if data[0] != 0x55 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("cmd-04 not response 0x55: 0x{:02x?}", data[0]),
-            ));
+        self.transact(&cmd, None, true).await?;
+        if let Some((values, time)) = self.chip_values.as_mut() {
+            values.bw = bw;
+            values.cr = cr;
+            values.sf = sf;
+            *time = Instant::now();
         }
-
         Ok(())
     }
 
-    pub async fn cmd05_write_data(&mut self, data: &[u8]) -> Result<(), IoError> {
-        if data.len() < 1 || data.len() > 16 {
+    async fn cmd05_write_data(&mut self, data: &[u8]) -> Result<(), IoError> {
+        if data.len() < 1 || data.len() > MAX_FRAME_LEN {
             return Err(IoError::from(ErrorKind::InvalidInput));
         }
 
@@ -289,49 +716,14 @@ impl IfroglabLora {
         cmd[2] = len;
         (&mut cmd[3..((len + 3) as usize)]).clone_from_slice(data);
         cmd[(len + 3) as usize] = crc(&cmd);
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd[..((len + 4) as usize)]).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
-        }
-        if data.len() != 1 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                "cmd-04 should with 1 byte",
-            ));
-        } else if data[0] != 0x55 {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("cmd-04 not response 0x55: 0x{:02x?}", data[0]),
-            ));
-        }
-
+        self.transact(&cmd[..((len + 4) as usize)], None, true)
+            .await?;
         Ok(())
     }
 
-    pub async fn cmd06_read_data(&mut self) -> Result<Option<ReadData>, IoError> {
+    async fn cmd06_read_data(&mut self) -> Result<Option<ReadData>, IoError> {
         let cmd: [u8; 4] = [0xC1, 0x06, 0, 0xC7];
-        let data;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            data = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => data,
-            };
-            break;
-        }
+        let data = self.transact(&cmd, None, false).await?;
         if data.len() == 0 {
             return Ok(None);
         } else if data.len() < 5 {
@@ -356,86 +748,193 @@ impl IfroglabLora {
         }))
     }
 
-    pub async fn cmd07_read_data_counter(&mut self) -> Result<u16, IoError> {
+    async fn cmd07_read_data_counter(&mut self) -> Result<u16, IoError> {
         let cmd: [u8; 4] = [0xC1, 0x07, 0, 0xC6];
-        let counter;
-        let start = Utc::now();
-        loop {
-            self.port.write(&cmd).await?;
-            counter = match self.read_ack().await {
-                Err(e) => {
-                    not_timeout(start, e)?;
-                    continue;
-                }
-                Ok(data) => {
-                    let mut dst = [0u8; 2];
-                    dst.clone_from_slice(data);
-                    u16::from_be_bytes(dst)
-                }
-            };
-            break;
+        let data = self.transact(&cmd, Some(2), false).await?;
+        let mut dst = [0u8; 2];
+        dst.clone_from_slice(data);
+        Ok(u16::from_be_bytes(dst))
+    }
+
+    async fn reopen(self: Box<Self>) -> Result<Box<dyn LoraPort>, (IoError, String)> {
+        match IfroglabLora::reopen(*self).await {
+            Err(e) => Err(e),
+            Ok(port) => Ok(Box::new(port)),
         }
+    }
+}
 
-        Ok(counter)
+impl IfroglabLoraBuilder {
+    /// Create a builder with default options in RX mode at 915.00 MHz with power level 0. Radio
+    /// values (bw, cr, sf) will not be applied if not specified.
+    pub fn new(path: &str) -> Self {
+        IfroglabLoraBuilder {
+            path: path.to_string(),
+            opts: IfroglabLoraOptions::default(),
+            mode: 3,
+            freq: 91500,
+            power: 0,
+            radio: None,
+        }
     }
 
-    /// Read ACK for the command from BYTE-4 (skip the first 3 bytes) with `len` size.
-    async fn read_ack(&mut self) -> Result<&[u8], IoError> {
-        time::sleep(Duration::from_millis(ACK_SLEEP_MS)).await;
-
-        // Read buffer and get the `len` field.
-        let mut size = self.port.read(&mut self.buff).await?;
-        if size < 3 {
-            // Second chance.
-            time::sleep(Duration::from_millis(ACK_SLEEP_MS)).await;
-            size = size + self.port.read(&mut self.buff[size..]).await?;
-            if size < 3 {
-                return Err(IoError::new(
-                    ErrorKind::TimedOut,
-                    format!("less than 3 bytes, only {} bytes", size),
-                ));
+    /// Serial port and ACK options.
+    pub fn options(mut self, opts: IfroglabLoraOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Operation mode. 0: sleep, 1: standby, 2: TX, 3: RX.
+    pub fn mode(mut self, mode: u8) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Frequency of 10,000 Hz. 86000~102000.
+    pub fn freq(mut self, freq: u32) -> Self {
+        self.freq = freq;
+        self
+    }
+
+    /// Power level. 0~15.
+    pub fn power(mut self, power: u8) -> Self {
+        self.power = power;
+        self
+    }
+
+    /// Bandwidth (1~3), code rate (1~4) and spreading factor (1~7).
+    pub fn radio(mut self, bw: u8, cr: u8, sf: u8) -> Self {
+        self.radio = Some((bw, cr, sf));
+        self
+    }
+
+    /// To validate all values.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        let mut checks = vec![
+            ("mode", self.mode as u32, 0, 3),
+            ("freq", self.freq, 86000, 102000),
+            ("power", self.power as u32, 0, 15),
+        ];
+        if let Some((bw, cr, sf)) = self.radio {
+            checks.push(("bw", bw as u32, 1, 3));
+            checks.push(("cr", cr as u32, 1, 4));
+            checks.push(("sf", sf as u32, 1, 7));
+        }
+        for (name, value, min, max) in checks {
+            if value < min || value > max {
+                return Err(BuildError::OutOfRange {
+                    name,
+                    value,
+                    min,
+                    max,
+                });
             }
         }
+        Ok(())
+    }
 
-        // Check if data length will be larger than reserved buffer size.
-        let len = self.buff[2] as usize;
-        if len + 4 > self.buff.len() {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("invalid `len` field: {}, buff: {:?}", len, self.buff),
-            ));
-        }
+    /// To open the port and apply settings.
+    ///
+    /// Returns the ready port and the chip information.
+    pub async fn build(self) -> Result<(IfroglabLora, ChipInfo), BuildError> {
+        self.validate()?;
+        let port = IfroglabLora::with_options(self.path.as_str(), self.opts)?;
+        self.setup(port).await
+    }
 
-        // Check if data is received completely.
-        if size < len + 4 {
-            // Second chance.
-            time::sleep(Duration::from_millis(ACK_SLEEP_MS)).await;
-            size = size + self.port.read(&mut self.buff[size..]).await?;
-            if size < len {
-                return Err(IoError::new(
-                    ErrorKind::TimedOut,
-                    format!("only receive {}/{} bytes", size, len),
-                ));
-            }
+    /// To apply settings to the opened port such as the port from [`detect()`]. The path and
+    /// options of the builder are not used.
+    ///
+    /// Returns the ready port and the chip information.
+    pub async fn setup(
+        self,
+        mut port: IfroglabLora,
+    ) -> Result<(IfroglabLora, ChipInfo), BuildError> {
+        self.validate()?;
+        let info = port.cmd00_chip_info().await?;
+        if let Some((bw, cr, sf)) = self.radio {
+            port.cmd04_set_values(bw, cr, sf).await?;
         }
+        port.cmd03_set_values(self.mode, self.freq, self.power)
+            .await?;
+        Ok((port, info))
+    }
+}
 
-        // Check CRC.
-        let crc = crc(&self.buff[..len + 3]);
-        if crc != self.buff[len + 3] {
-            return Err(IoError::new(
-                ErrorKind::InvalidData,
-                format!("invalid CRC: {:?}, size: {}", self.buff, size),
-            ));
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::OutOfRange {
+                name,
+                value,
+                min,
+                max,
+            } => write!(f, "{} {} out of range {}~{}", name, value, min, max),
+            BuildError::Io(e) => write!(f, "{}", e),
         }
+    }
+}
 
-        if self.buff[1] == 0xff {
-            return Err(IoError::new(
-                ErrorKind::Other,
-                format!("{:02x?}", self.buff[3]),
-            ));
+impl StdError for BuildError {}
+
+impl From<IoError> for BuildError {
+    fn from(e: IoError) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+/// To detect the dongle from USB serial ports that match the filter. Ports in `exclude` (used by
+/// other dongles) will be skipped. Each candidate is probed with command 0x00 and the first one
+/// that answers will be used.
+///
+/// Returns the port and its device path.
+pub async fn detect(
+    filter: UsbFilter,
+    exclude: &[String],
+    opts: IfroglabLoraOptions,
+) -> Result<(IfroglabLora, String), IoError> {
+    let ports = match tokio_serial::available_ports() {
+        Err(e) => return Err(IoError::other(e)),
+        Ok(ports) => ports,
+    };
+    for info in ports {
+        let usb = match info.port_type {
+            SerialPortType::UsbPort(usb) => usb,
+            _ => continue,
+        };
+        if filter.vid.is_some_and(|vid| vid != usb.vid)
+            || filter.pid.is_some_and(|pid| pid != usb.pid)
+            || exclude.contains(&info.port_name)
+        {
+            continue;
+        }
+        let mut port = match IfroglabLora::with_options(info.port_name.as_str(), opts) {
+            Err(_) => continue,
+            Ok(port) => port,
+        };
+        let timeout = Duration::from_millis(PROBE_TIMEOUT_MS);
+        if let Ok(Ok(_)) = time::timeout(timeout, port.cmd00_chip_info()).await {
+            return Ok((port, info.port_name));
         }
+    }
+    Err(IoError::new(ErrorKind::NotFound, "no dongle detected"))
+}
 
-        Ok(&self.buff[3..len + 3])
+/// To parse one ACK frame `[id, status, len, data..., crc]` from the front of the buffer.
+///
+/// Returns the frame size so that the caller can consume exactly one frame and leave the
+/// remainder for the next frame.
+fn parse_frame(buff: &[u8]) -> Frame {
+    if buff.len() < 3 {
+        return Frame::Incomplete;
+    }
+    let size = buff[2] as usize + 4;
+    if buff.len() < size {
+        return Frame::Incomplete;
+    }
+    match crc(&buff[..size - 1]) == buff[size - 1] {
+        false => Frame::InvalidCrc(size),
+        true => Frame::Complete(size),
     }
 }
 
@@ -448,9 +947,24 @@ fn crc(data: &[u8]) -> u8 {
     result
 }
 
-fn not_timeout(start: DateTime<Utc>, err: IoError) -> Result<(), IoError> {
-    match Utc::now().timestamp_millis() - start.timestamp_millis() > TIMEOUT_MS {
-        false => Ok(()),
-        true => Err(err),
+impl Retry {
+    /// To check if the command can be retried. Returns the error after timeout or exceeding the
+    /// maximum retry times.
+    fn check(&mut self, err: IoError) -> Result<(), IoError> {
+        if Utc::now().timestamp_millis() - self.start.timestamp_millis() > self.timeout_ms {
+            return Err(err);
+        }
+        if let Some(max_retries) = self.max_retries {
+            if self.retries >= max_retries {
+                return Err(err);
+            }
+        }
+        self.retries += 1;
+        Ok(())
+    }
+
+    /// The attempt number of the command from 1.
+    fn attempt(&self) -> usize {
+        self.retries + 1
     }
 }
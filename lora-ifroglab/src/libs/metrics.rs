@@ -0,0 +1,442 @@
+//! Gateway metrics that can be exported in Prometheus text format.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Counters and gauges of the gateway.
+#[derive(Default)]
+pub struct Metrics {
+    /// Uplink frames received from the dongle.
+    pub uldata_received: AtomicU64,
+    /// RX frames implied by the dongle RX counter increments.
+    pub rx_counted: AtomicU64,
+    /// RX frames read with command 0x06.
+    pub rx_read: AtomicU64,
+    /// RX drains that read at least one frame.
+    pub rx_drains: AtomicU64,
+    /// Uplink data published to the message queue.
+    pub uldata_published: AtomicU64,
+    /// Uplink frames filtered by RSSI.
+    pub uldata_filtered: AtomicU64,
+    /// Uplink frames dropped because the devices are not added by the broker.
+    pub uldata_unknown: AtomicU64,
+    /// Uplink frames dropped because they are identical to the previous frames.
+    pub uldata_duplicated: AtomicU64,
+    /// Errors when publishing uplink data or downlink data results to the message queue.
+    pub publish_errors: AtomicU64,
+    /// Uplink data buffered while the message queue is not ready.
+    pub uldata_buffered: AtomicU64,
+    /// Buffered uplink data dropped because the offline buffer is full.
+    pub uldata_buffer_dropped: AtomicU64,
+    /// Message queue disconnections.
+    pub mq_disconnects: AtomicU64,
+    /// Device control messages rejected because of invalid addresses or too large ranges.
+    pub ctrl_rejected: AtomicU64,
+    /// Downlink data results buffered because they cannot be sent to the broker.
+    pub results_buffered: AtomicU64,
+    /// Buffered downlink data results dropped because the result buffer is full.
+    pub results_buffer_dropped: AtomicU64,
+    /// Downlink data queued from the broker.
+    pub dldata_queued: AtomicU64,
+    /// Downlink data skipped because the data IDs are queued or recently received.
+    pub dldata_duplicated: AtomicU64,
+    /// Queuing downlink data replaced by newer data of the same address.
+    pub dldata_superseded: AtomicU64,
+    /// Downlink data transmitted to nodes.
+    pub dldata_sent: AtomicU64,
+    /// Downlink data expired before transmission.
+    pub dldata_expired: AtomicU64,
+    /// Downlink data kept in the queue because the TX budget of the node is exhausted.
+    pub dldata_deferred: AtomicU64,
+    /// Downlink data acknowledged by nodes.
+    pub dldata_delivered: AtomicU64,
+    /// Downlink data without acknowledgments after all retries.
+    pub dldata_undelivered: AtomicU64,
+    /// The sum of latency from the published time to the TX completion in milliseconds.
+    pub dldata_latency_ms: AtomicU64,
+    /// Transmitted downlink data with valid published time that are counted in the latency.
+    pub dldata_latency_samples: AtomicU64,
+    /// The sum of estimated airtime of the TX frames in milliseconds.
+    pub dldata_airtime_ms: AtomicU64,
+    /// Errors when transmitting downlink data.
+    pub tx_errors: AtomicU64,
+    /// Serial port reconnections.
+    pub reconnects: AtomicU64,
+    /// Dongle resets because of consecutive invalid data errors or command failures.
+    pub resets: AtomicU64,
+    /// Invalid CRC of the dongle ACK frames.
+    pub crc_errors: AtomicU64,
+    /// Health checks of the dongle.
+    pub health_checks: AtomicU64,
+    /// Failed health checks of the dongle.
+    pub health_check_errors: AtomicU64,
+    /// The last RSSI of each node.
+    pub last_rssi: Mutex<HashMap<String, i16>>,
+    /// The result of the last health check.
+    pub last_health_check: Mutex<Option<HealthCheck>>,
+    /// The queued time in milliseconds of the latest transmitted downlink data.
+    pub queued_ms: Mutex<VecDeque<u64>>,
+}
+
+/// Statistics of the queued time of the latest transmitted downlink data.
+#[derive(Clone, Serialize)]
+pub struct QueuedMsStats {
+    /// The number of samples.
+    pub count: usize,
+    pub p50: u64,
+    pub p95: u64,
+    pub max: u64,
+}
+
+/// The result of one health check.
+#[derive(Clone)]
+pub struct HealthCheck {
+    /// The check time.
+    pub time: DateTime<Utc>,
+    /// The error message. `None` means that the dongle is healthy.
+    pub error: Option<String>,
+}
+
+/// The function to get one counter field.
+type Field = fn(&Metrics) -> &AtomicU64;
+
+const PREFIX: &'static str = "lora_ifroglab";
+/// The number of the latest samples of the queued time.
+const QUEUED_MS_SAMPLES: usize = 100;
+
+/// Counters with the name, the help message and the field.
+const COUNTERS: [(&'static str, &'static str, Field); 32] = [
+    (
+        "uldata_received_total",
+        "Uplink frames received from the dongle.",
+        |m| &m.uldata_received,
+    ),
+    (
+        "rx_counted_total",
+        "RX frames implied by the dongle RX counter increments.",
+        |m| &m.rx_counted,
+    ),
+    ("rx_read_total", "RX frames read from the dongle.", |m| {
+        &m.rx_read
+    }),
+    (
+        "rx_drains_total",
+        "RX drains that read at least one frame.",
+        |m| &m.rx_drains,
+    ),
+    (
+        "uldata_published_total",
+        "Uplink data published to the message queue.",
+        |m| &m.uldata_published,
+    ),
+    (
+        "uldata_filtered_total",
+        "Uplink frames filtered by RSSI.",
+        |m| &m.uldata_filtered,
+    ),
+    (
+        "uldata_unknown_total",
+        "Uplink frames dropped because the devices are not added by the broker.",
+        |m| &m.uldata_unknown,
+    ),
+    (
+        "uldata_duplicated_total",
+        "Uplink frames dropped because they are identical to the previous frames.",
+        |m| &m.uldata_duplicated,
+    ),
+    (
+        "publish_errors_total",
+        "Errors when publishing uplink data or downlink data results to the message queue.",
+        |m| &m.publish_errors,
+    ),
+    (
+        "uldata_buffered_total",
+        "Uplink data buffered while the message queue is not ready.",
+        |m| &m.uldata_buffered,
+    ),
+    (
+        "uldata_buffer_dropped_total",
+        "Buffered uplink data dropped because the offline buffer is full.",
+        |m| &m.uldata_buffer_dropped,
+    ),
+    (
+        "mq_disconnects_total",
+        "Message queue disconnections.",
+        |m| &m.mq_disconnects,
+    ),
+    (
+        "ctrl_rejected_total",
+        "Device control messages rejected because of invalid addresses or too large ranges.",
+        |m| &m.ctrl_rejected,
+    ),
+    (
+        "results_buffered_total",
+        "Downlink data results buffered because they cannot be sent to the broker.",
+        |m| &m.results_buffered,
+    ),
+    (
+        "results_buffer_dropped_total",
+        "Buffered downlink data results dropped because the result buffer is full.",
+        |m| &m.results_buffer_dropped,
+    ),
+    (
+        "dldata_queued_total",
+        "Downlink data queued from the broker.",
+        |m| &m.dldata_queued,
+    ),
+    (
+        "dldata_duplicated_total",
+        "Downlink data skipped because the data IDs are queued or recently received.",
+        |m| &m.dldata_duplicated,
+    ),
+    (
+        "dldata_superseded_total",
+        "Queuing downlink data replaced by newer data of the same address.",
+        |m| &m.dldata_superseded,
+    ),
+    (
+        "dldata_sent_total",
+        "Downlink data transmitted to nodes.",
+        |m| &m.dldata_sent,
+    ),
+    (
+        "dldata_expired_total",
+        "Downlink data expired before transmission.",
+        |m| &m.dldata_expired,
+    ),
+    (
+        "dldata_deferred_total",
+        "Downlink data kept in the queue because the TX budget of the node is exhausted.",
+        |m| &m.dldata_deferred,
+    ),
+    (
+        "dldata_delivered_total",
+        "Downlink data acknowledged by nodes.",
+        |m| &m.dldata_delivered,
+    ),
+    (
+        "dldata_undelivered_total",
+        "Downlink data without acknowledgments after all retries.",
+        |m| &m.dldata_undelivered,
+    ),
+    (
+        "dldata_latency_ms_total",
+        "The sum of latency from the published time to the TX completion in milliseconds.",
+        |m| &m.dldata_latency_ms,
+    ),
+    (
+        "dldata_latency_samples_total",
+        "Transmitted downlink data with valid published time that are counted in the latency.",
+        |m| &m.dldata_latency_samples,
+    ),
+    (
+        "dldata_airtime_ms_total",
+        "The sum of estimated airtime of the TX frames in milliseconds.",
+        |m| &m.dldata_airtime_ms,
+    ),
+    (
+        "tx_errors_total",
+        "Errors when transmitting downlink data.",
+        |m| &m.tx_errors,
+    ),
+    (
+        "serial_reconnects_total",
+        "Serial port reconnections.",
+        |m| &m.reconnects,
+    ),
+    (
+        "dongle_resets_total",
+        "Dongle resets because of consecutive invalid data errors or command failures.",
+        |m| &m.resets,
+    ),
+    (
+        "crc_errors_total",
+        "Invalid CRC of the dongle ACK frames.",
+        |m| &m.crc_errors,
+    ),
+    ("health_checks_total", "Health checks of the dongle.", |m| {
+        &m.health_checks
+    }),
+    (
+        "health_check_errors_total",
+        "Failed health checks of the dongle.",
+        |m| &m.health_check_errors,
+    ),
+];
+
+impl Metrics {
+    /// To increase one counter by 1.
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// To record the queued time in milliseconds of one transmitted downlink data.
+    pub fn record_queued_ms(&self, queued_ms: u64) {
+        let mut mutex = self.queued_ms.lock().unwrap();
+        (*mutex).push_back(queued_ms);
+        if (*mutex).len() > QUEUED_MS_SAMPLES {
+            (*mutex).pop_front();
+        }
+    }
+
+    /// To get statistics of the queued time of the latest transmitted downlink data. `None` means
+    /// no samples.
+    pub fn queued_ms_stats(&self) -> Option<QueuedMsStats> {
+        let mut samples: Vec<u64> = {
+            let mutex = self.queued_ms.lock().unwrap();
+            (*mutex).iter().copied().collect()
+        };
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100) - 1];
+        Some(QueuedMsStats {
+            count: samples.len(),
+            p50: percentile(50),
+            p95: percentile(95),
+            max: samples[samples.len() - 1],
+        })
+    }
+
+    /// To get all counters with camel case names without the `_total` suffix. For example,
+    /// `uldataReceived`.
+    pub fn counters(&self) -> Vec<(String, u64)> {
+        COUNTERS
+            .iter()
+            .map(|(name, _, field)| {
+                let mut key = String::new();
+                for (i, word) in name.trim_end_matches("_total").split('_').enumerate() {
+                    match i {
+                        0 => key.push_str(word),
+                        _ => {
+                            let mut chars = word.chars();
+                            if let Some(c) = chars.next() {
+                                key.push(c.to_ascii_uppercase());
+                                key.push_str(chars.as_str());
+                            }
+                        }
+                    }
+                }
+                (key, field(self).load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+
+    /// To export metrics in Prometheus text format.
+    ///
+    /// `dongles` are metrics of each dongle with the `dongle` label (device path). `queue_depth` is
+    /// the current downlink queue depth of each address. `offline_depth` is the current uplink
+    /// data in the offline buffer.
+    pub fn export(
+        &self,
+        dongles: &[(&str, &Metrics)],
+        queue_depth: &HashMap<String, usize>,
+        offline_depth: usize,
+    ) -> String {
+        let mut out = String::new();
+        let mut list: Vec<(String, &Metrics)> = vec![("".to_string(), self)];
+        for (dongle, metrics) in dongles.iter() {
+            list.push((format!("dongle=\"{}\"", dongle), *metrics));
+        }
+
+        for (name, help, field) in COUNTERS {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} counter", PREFIX, name);
+            for (labels, metrics) in list.iter() {
+                let value = field(metrics).load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}_{}{} {}", PREFIX, name, braces(labels), value);
+            }
+        }
+
+        // Averages with the name, the help message, the sum field and the count field.
+        let averages: [(&'static str, &'static str, Field, Field); 3] = [
+            (
+                "dldata_latency_ms_avg",
+                "The average latency from the published time to the TX completion in milliseconds.",
+                |m| &m.dldata_latency_ms,
+                |m| &m.dldata_latency_samples,
+            ),
+            (
+                "dldata_airtime_ms_avg",
+                "The average estimated airtime of the TX frames in milliseconds.",
+                |m| &m.dldata_airtime_ms,
+                |m| &m.dldata_sent,
+            ),
+            (
+                "rx_drain_frames_avg",
+                "The average RX frames read in one drain.",
+                |m| &m.rx_read,
+                |m| &m.rx_drains,
+            ),
+        ];
+        for (name, help, field, count) in averages {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} gauge", PREFIX, name);
+            for (labels, metrics) in list.iter() {
+                let avg = match count(metrics).load(Ordering::Relaxed) {
+                    0 => 0.0,
+                    count => field(metrics).load(Ordering::Relaxed) as f64 / count as f64,
+                };
+                let _ = writeln!(out, "{}_{}{} {}", PREFIX, name, braces(labels), avg);
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {}_queue_depth Queuing downlink data of the node.",
+            PREFIX
+        );
+        let _ = writeln!(out, "# TYPE {}_queue_depth gauge", PREFIX);
+        for (addr, depth) in queue_depth.iter() {
+            let _ = writeln!(
+                out,
+                "{}_queue_depth{{network_addr=\"{}\"}} {}",
+                PREFIX, addr, depth
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP {}_offline_buffer_depth Buffered uplink data while the message queue is not ready.",
+            PREFIX
+        );
+        let _ = writeln!(out, "# TYPE {}_offline_buffer_depth gauge", PREFIX);
+        let _ = writeln!(out, "{}_offline_buffer_depth {}", PREFIX, offline_depth);
+
+        let _ = writeln!(
+            out,
+            "# HELP {}_last_rssi The last RSSI of the node.",
+            PREFIX
+        );
+        let _ = writeln!(out, "# TYPE {}_last_rssi gauge", PREFIX);
+        for (labels, metrics) in list.iter() {
+            let mutex = metrics.last_rssi.lock().unwrap();
+            for (addr, rssi) in (*mutex).iter() {
+                let labels = match labels.len() {
+                    0 => format!("network_addr=\"{}\"", addr),
+                    _ => format!("network_addr=\"{}\",{}", addr, labels),
+                };
+                let _ = writeln!(out, "{}_last_rssi{} {}", PREFIX, braces(&labels), rssi);
+            }
+        }
+        out
+    }
+}
+
+/// To wrap labels with braces. Empty labels will be empty string.
+fn braces(labels: &str) -> String {
+    match labels.len() {
+        0 => "".to_string(),
+        _ => format!("{{{}}}", labels),
+    }
+}
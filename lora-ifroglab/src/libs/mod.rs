@@ -1,9 +1,18 @@
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
+pub mod ack_tracker;
 pub mod config;
+pub mod crypto;
 pub mod data_handler;
+pub mod lora_sim;
 pub mod lora_task;
 pub mod lora_usb;
+pub mod metrics;
+pub mod queue_file;
+pub mod result_buffer;
+pub mod ul_decoder;
 
 #[derive(Clone, Debug, Serialize)]
 pub struct UlData {
@@ -12,16 +21,30 @@ pub struct UlData {
     pub network_addr: String,
     pub data: String,
     pub extension: UlDataExt,
+    /// `true` means that the data is filtered and not sent to the broker.
+    pub filtered: bool,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct UlDataExt {
     pub rssi: i16,
+    /// `true` means that the payload cannot be decrypted and is sent as is.
+    pub encrypted: bool,
+    /// The frequency of the gateway.
+    pub freq: u32,
+    /// The RX data counter of the dongle when reading the data.
+    pub counter: u16,
+    /// The time when the gateway received the data.
+    #[serde(rename = "gatewayRecvTime")]
+    pub gateway_recv_time: String,
+    /// Fields of uplink decoders.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decoded: Option<Map<String, Value>>,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DlData {
-    #[serde(skip_serializing)]
+    #[serde(skip)]
     pub data_id: String,
     pub time: String,
     #[serde(rename = "pub")]
@@ -30,6 +53,145 @@ pub struct DlData {
     #[serde(rename = "networkAddr")]
     pub network_addr: String,
     pub data: String,
+    /// Failed transmission attempts. This is internal and only persisted in the queue file.
+    #[serde(skip)]
+    pub attempts: u32,
+    /// Data with higher priority will be sent first.
+    pub priority: i64,
+    /// Retransmissions because of no acknowledgment.
+    #[serde(rename = "ackRetries", default)]
+    pub ack_retries: u32,
+    /// The data will not be transmitted after this time.
+    #[serde(rename = "expiresAt", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The latency from the published time to the TX completion in milliseconds. `None` means
+    /// that the published time cannot be parsed.
+    #[serde(rename = "latencyMs", default)]
+    pub latency_ms: Option<u64>,
+    /// The waiting time from the published time to the start of the transmission in
+    /// milliseconds. `None` means that the published time cannot be parsed.
+    #[serde(rename = "queuedMs", default)]
+    pub queued_ms: Option<u64>,
+    /// The status of the [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) when the TX
+    /// path concludes. `None` means that the data is not concluded.
+    #[serde(default)]
+    pub result: Option<i32>,
+    /// The message of the result.
+    #[serde(rename = "resultMessage", default)]
+    pub result_message: Option<String>,
+    /// The estimated airtime of the TX frame in milliseconds.
+    #[serde(rename = "airtimeMs", default)]
+    pub airtime_ms: u64,
+    /// The frequency (10kHz) of the TX frame.
+    #[serde(rename = "txFreq", default, skip_serializing_if = "Option::is_none")]
+    pub tx_freq: Option<u32>,
+}
+
+/// The status of one node from its uplink data.
+#[derive(Clone, Debug)]
+pub struct NodeStatus {
+    /// The last time that the node is heard.
+    pub last_seen: DateTime<Utc>,
+    /// The RSSI of the last uplink data.
+    pub last_rssi: i16,
+    /// Received uplink frames including duplicated and filtered frames.
+    pub uplink_count: u64,
+    /// `true` means that the node is not heard in `nodeOfflineSecs`.
+    pub offline: bool,
+}
+
+/// The offline or online event of one node.
+#[derive(Clone, Debug, Serialize)]
+pub struct NodeEvent {
+    pub time: String,
+    #[serde(rename = "networkAddr")]
+    pub network_addr: String,
+    /// [`NODE_EVENT_OFFLINE`] or [`NODE_EVENT_ONLINE`].
+    pub event: &'static str,
+}
+
+/// The token bucket to limit transmitted downlink data of one node. The bucket holds at most
+/// `per_minute` tokens and refills continuously.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    /// The time (ms) of the last refill.
+    updated_ms: i64,
 }
 
 const MAX_DATA: usize = 100;
+
+/// The size of the node address and the reserved field in front of the payload.
+pub const FRAME_HEADER_LEN: usize = 8;
+/// The maximum downlink payload size in bytes limited by the dongle frame size.
+pub const MAX_DL_PAYLOAD: usize = lora_usb::MAX_FRAME_LEN - FRAME_HEADER_LEN;
+
+/// The node is not heard in `nodeOfflineSecs`.
+pub const NODE_EVENT_OFFLINE: &'static str = "offline";
+/// The offline node is heard again.
+pub const NODE_EVENT_ONLINE: &'static str = "online";
+
+/// The network address for broadcast downlink data.
+pub const BROADCAST_ADDR: &'static str = "00000000";
+
+impl TokenBucket {
+    /// To create the full bucket at `now_ms`.
+    pub fn new(per_minute: u32, now_ms: i64) -> Self {
+        TokenBucket {
+            tokens: per_minute as f64,
+            updated_ms: now_ms,
+        }
+    }
+
+    /// To take one token at `now_ms`.
+    ///
+    /// Returns `false` if the budget is exhausted.
+    pub fn take(&mut self, per_minute: u32, now_ms: i64) -> bool {
+        self.tokens = self.available(per_minute, now_ms);
+        self.updated_ms = self.updated_ms.max(now_ms);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// The available tokens at `now_ms`.
+    pub fn available(&self, per_minute: u32, now_ms: i64) -> f64 {
+        let elapsed_ms = (now_ms - self.updated_ms).max(0) as f64;
+        (self.tokens + elapsed_ms * per_minute as f64 / 60000.0).min(per_minute as f64)
+    }
+}
+
+/// To format the node ID as the network address (8-character lowercase hexadecimal string).
+pub fn fmt_addr(node_id: u32) -> String {
+    format!("{:08x}", node_id)
+}
+
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: received by the gateway.
+pub const DL_RESULT_RECEIVED: i32 = -1;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: transmitted to the node and
+/// waiting for the acknowledgment.
+pub const DL_RESULT_TRANSMITTED: i32 = -2;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: transmitted to the node.
+pub const DL_RESULT_SENT: i32 = 0;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: invalid data.
+pub const DL_RESULT_INVALID: i32 = 1;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: expired in the queue.
+pub const DL_RESULT_EXPIRED: i32 = 2;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: failed to transmit.
+pub const DL_RESULT_TX_FAILED: i32 = 3;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: the queue is full.
+pub const DL_RESULT_QUEUE_FULL: i32 = 4;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: the device is not added by
+/// the broker.
+pub const DL_RESULT_UNKNOWN_DEVICE: i32 = 5;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: canceled because the gateway
+/// is shutting down or the device is deleted by the broker.
+pub const DL_RESULT_CANCELED: i32 = 6;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: no acknowledgment from the
+/// node after all retries.
+pub const DL_RESULT_UNDELIVERED: i32 = 7;
+/// [`DlDataResult`](sylvia_iot_sdk::mq::network::DlDataResult) status: replaced by newer data of
+/// the same address.
+pub const DL_RESULT_SUPERSEDED: i32 = 8;
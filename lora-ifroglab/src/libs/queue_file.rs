@@ -0,0 +1,91 @@
+//! To persist queuing downlink data in a JSON file.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{Error as IoError, ErrorKind},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::DlData;
+
+/// The file content of one queuing downlink data.
+#[derive(Deserialize, Serialize)]
+struct FileData {
+    #[serde(rename = "dataId")]
+    data_id: String,
+    #[serde(default)]
+    attempts: u32,
+    #[serde(flatten)]
+    data: DlData,
+}
+
+/// To load queuing downlink data from the file.
+///
+/// A missing file means an empty queue. Corrupted files will be logged and ignored. Expired data
+/// will be removed by the sweep task of [`LoraTask`](super::lora_task::LoraTask) with results.
+pub fn load(path: &str) -> HashMap<String, VecDeque<DlData>> {
+    const FN_NAME: &'static str = "queue_file::load";
+
+    let content = match fs::read_to_string(path) {
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                warn!("[{}] read {} error: {}", FN_NAME, path, e);
+            }
+            return HashMap::new();
+        }
+        Ok(content) => content,
+    };
+    let file: HashMap<String, Vec<FileData>> = match serde_json::from_str(content.as_str()) {
+        Err(e) => {
+            warn!("[{}] ignore corrupted file {}: {}", FN_NAME, path, e);
+            return HashMap::new();
+        }
+        Ok(file) => file,
+    };
+    file.into_iter()
+        .map(|(addr, list)| {
+            let queue = list
+                .into_iter()
+                .map(|item| DlData {
+                    data_id: item.data_id,
+                    attempts: item.attempts,
+                    ..item.data
+                })
+                .collect();
+            (addr, queue)
+        })
+        .collect()
+}
+
+/// To serialize queuing downlink data into the file content.
+pub fn serialize(queue: &HashMap<String, VecDeque<DlData>>) -> Result<String, IoError> {
+    let file: HashMap<&String, Vec<FileData>> = queue
+        .iter()
+        .filter(|(_, list)| !list.is_empty())
+        .map(|(addr, list)| {
+            let list = list
+                .iter()
+                .map(|data| FileData {
+                    data_id: data.data_id.clone(),
+                    attempts: data.attempts,
+                    data: data.clone(),
+                })
+                .collect();
+            (addr, list)
+        })
+        .collect();
+    match serde_json::to_string(&file) {
+        Err(e) => Err(IoError::new(ErrorKind::InvalidData, e)),
+        Ok(content) => Ok(content),
+    }
+}
+
+/// To write the content into the file. A temporary file is used to prevent partial writes.
+pub fn save(path: &str, content: &str) -> Result<(), IoError> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(tmp_path.as_str(), content)?;
+    fs::rename(tmp_path.as_str(), path)
+}
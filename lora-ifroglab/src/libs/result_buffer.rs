@@ -0,0 +1,113 @@
+//! A bounded buffer of downlink data results that cannot be sent to the broker.
+//!
+//! Results are sent in order. Once one result is buffered, later results are buffered behind it
+//! until the buffer is flushed.
+
+use std::{collections::VecDeque, error::Error as StdError, sync::Mutex};
+
+use log::warn;
+use sylvia_iot_sdk::mq::{
+    network::{DlDataResult, NetworkMgr},
+    MgrStatus,
+};
+
+use super::metrics::Metrics;
+
+/// The buffer shared by the data handler and LoRa tasks.
+pub struct ResultBuffer {
+    results: Mutex<VecDeque<DlDataResult>>,
+    /// The maximum number of buffered results. `0` to disable buffering.
+    max: usize,
+}
+
+impl ResultBuffer {
+    /// To create the buffer with the maximum number of results. `0` to disable buffering.
+    pub fn new(max: usize) -> Self {
+        ResultBuffer {
+            results: Mutex::new(VecDeque::new()),
+            max,
+        }
+    }
+
+    /// To send the result, or buffer it if sending fails or there are buffered results. The oldest
+    /// result will be dropped if the buffer is full.
+    ///
+    /// Returns the send error. The result has been buffered if buffering is enabled.
+    pub fn send(
+        &self,
+        mgr: &NetworkMgr,
+        result: &DlDataResult,
+        metrics: &Metrics,
+    ) -> Result<(), Box<dyn StdError>> {
+        if self.max == 0 {
+            return mgr.send_dldata_result(result);
+        }
+        let mut mutex = self.results.lock().unwrap();
+        if !(*mutex).is_empty() {
+            // Keep the order behind buffered results.
+            self.push(&mut mutex, result.clone(), metrics);
+            return Ok(());
+        }
+        match mgr.send_dldata_result(result) {
+            Err(e) => {
+                self.push(&mut mutex, result.clone(), metrics);
+                Err(e)
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// The number of buffered results.
+    pub fn len(&self) -> usize {
+        self.results.lock().unwrap().len()
+    }
+
+    /// Returns `true` if there are no buffered results.
+    pub fn is_empty(&self) -> bool {
+        self.results.lock().unwrap().is_empty()
+    }
+
+    /// To send buffered results in order when the message queue is ready. The remaining results
+    /// will be kept if sending fails.
+    ///
+    /// Returns `false` if there are remaining results.
+    pub fn flush(&self, mgr: &NetworkMgr, metrics: &Metrics) -> bool {
+        const FN_NAME: &'static str = "ResultBuffer::flush";
+
+        let mut mutex = self.results.lock().unwrap();
+        if (*mutex).is_empty() {
+            return true;
+        }
+        if mgr.status() != MgrStatus::Ready {
+            return false;
+        }
+        while let Some(result) = (*mutex).front() {
+            if let Err(e) = mgr.send_dldata_result(result) {
+                warn!("[{}] send result {} error: {}", FN_NAME, result.data_id, e);
+                Metrics::inc(&metrics.publish_errors);
+                return false;
+            }
+            (*mutex).pop_front();
+        }
+        true
+    }
+
+    /// The buffered results in order.
+    pub fn pending(&self) -> Vec<DlDataResult> {
+        let mutex = self.results.lock().unwrap();
+        (*mutex).iter().cloned().collect()
+    }
+
+    fn push(&self, results: &mut VecDeque<DlDataResult>, result: DlDataResult, metrics: &Metrics) {
+        const FN_NAME: &'static str = "ResultBuffer::push";
+
+        if results.len() >= self.max {
+            if let Some(dropped) = results.pop_front() {
+                warn!("[{}] drop buffered result {}", FN_NAME, dropped.data_id);
+                Metrics::inc(&metrics.results_buffer_dropped);
+            }
+        }
+        results.push_back(result);
+        Metrics::inc(&metrics.results_buffered);
+    }
+}
@@ -0,0 +1,112 @@
+//! Decoders to enrich uplink data before sending to the broker.
+//!
+//! Decoders are selected by network address prefixes. Decoded fields are merged into the
+//! extension of the uplink data and never replace the fields of the gateway such as `rssi`. The
+//! raw payload is always sent whether decoders succeed or not.
+
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind},
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use log::warn;
+use serde_json::{json, Map, Value};
+
+/// The uplink payload decoder.
+pub trait UlDecoder: Send + Sync {
+    /// To decode the payload of the node `addr`. Returns `None` if the payload cannot be decoded.
+    fn decode(&self, addr: &str, payload: &[u8]) -> Option<Map<String, Value>>;
+}
+
+/// Decoders with address prefixes.
+#[derive(Clone, Default)]
+pub struct UlDecoders {
+    /// Sorted by the prefix length so that decoders of longer prefixes run later.
+    decoders: Vec<(String, Arc<dyn UlDecoder>)>,
+}
+
+/// The built-in decoder that decodes the 4-byte big-endian sequence number such as uplink data of
+/// simulated dongles.
+///
+/// Output fields:
+/// - `seq`: the sequence number.
+pub struct SeqDecoder;
+
+/// Name of [`SeqDecoder`].
+pub const DECODER_SEQ: &'static str = "seq";
+/// Names of all built-in decoders.
+pub const DECODER_NAMES: &'static [&'static str] = &[DECODER_SEQ];
+
+impl UlDecoder for SeqDecoder {
+    fn decode(&self, _addr: &str, payload: &[u8]) -> Option<Map<String, Value>> {
+        if payload.len() != 4 {
+            return None;
+        }
+        let mut seq = [0u8; 4];
+        seq.copy_from_slice(payload);
+        let mut result = Map::new();
+        result.insert("seq".to_string(), json!(u32::from_be_bytes(seq)));
+        Some(result)
+    }
+}
+
+impl UlDecoders {
+    /// To create decoders from the map of address prefixes and built-in decoder names. Prefixes
+    /// will be converted to lowercase.
+    pub fn new(decoders: &HashMap<String, String>) -> Result<Self, IoError> {
+        let mut result = vec![];
+        for (prefix, name) in decoders.iter() {
+            match new_decoder(name.as_str()) {
+                None => {
+                    let e = format!("unknown decoder {} of {}", name, prefix);
+                    return Err(IoError::new(ErrorKind::InvalidInput, e));
+                }
+                Some(decoder) => result.push((prefix.to_lowercase(), decoder)),
+            }
+        }
+        result.sort_by(|a, b| a.0.len().cmp(&b.0.len()).then(a.0.cmp(&b.0)));
+        Ok(UlDecoders { decoders: result })
+    }
+
+    /// To add a custom decoder for addresses with the prefix.
+    pub fn add(&mut self, prefix: &str, decoder: Arc<dyn UlDecoder>) {
+        self.decoders.push((prefix.to_lowercase(), decoder));
+        self.decoders
+            .sort_by(|a, b| a.0.len().cmp(&b.0.len()).then(a.0.cmp(&b.0)));
+    }
+
+    /// Returns `true` if there are no decoders.
+    pub fn is_empty(&self) -> bool {
+        self.decoders.is_empty()
+    }
+
+    /// To run all matched decoders and returns merged fields. Panics of decoders are caught and
+    /// logged.
+    pub fn decode(&self, addr: &str, payload: &[u8]) -> Map<String, Value> {
+        const FN_NAME: &'static str = "UlDecoders::decode";
+
+        let mut result = Map::new();
+        for (prefix, decoder) in self.decoders.iter() {
+            if !addr.starts_with(prefix.as_str()) {
+                continue;
+            }
+            let decoder = AssertUnwindSafe(decoder);
+            match panic::catch_unwind(|| decoder.decode(addr, payload)) {
+                Err(_) => warn!("[{}] decoder of {} panics for {}", FN_NAME, prefix, addr),
+                Ok(None) => (),
+                Ok(Some(fields)) => result.extend(fields),
+            }
+        }
+        result
+    }
+}
+
+/// To create the built-in decoder with the name.
+pub fn new_decoder(name: &str) -> Option<Arc<dyn UlDecoder>> {
+    match name {
+        DECODER_SEQ => Some(Arc::new(SeqDecoder)),
+        _ => None,
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+use axum::{extract::State, http::header, response::IntoResponse, routing, Router};
+
+use super::State as AppState;
+use crate::libs::metrics::Metrics;
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(scope_path, routing::get(get_metrics))
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/metrics`
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let queue_depth: HashMap<String, usize> = {
+        let mutex = state.queue_dldata.lock().unwrap();
+        (*mutex)
+            .iter()
+            .map(|(addr, queue)| (addr.clone(), queue.len()))
+            .collect()
+    };
+    let offline_depth = { state.offline_uldata.lock().unwrap().len() };
+    let dongles: Vec<(&str, &Metrics)> = state
+        .dongles
+        .iter()
+        .map(|dongle| (dongle.dev_path.as_str(), dongle.metrics.as_ref()))
+        .collect();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state
+            .metrics
+            .export(dongles.as_slice(), &queue_depth, offline_depth),
+    )
+}
@@ -1,21 +1,45 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     error::Error as StdError,
     io::{Error as IoError, ErrorKind},
+    path::Path,
     sync::{Arc, Mutex},
 };
 
-use axum::Router;
-use sylvia_iot_sdk::mq::{network::NetworkMgr, Connection, Options as MgrOptions};
+use axum::{
+    extract::{self, Request},
+    http::{header, Method},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use sylvia_iot_sdk::{
+    mq::{
+        network::{NetworkMgr, UlData as NetUlData},
+        Connection, Options as MgrOptions,
+    },
+    util::err::ErrResp,
+};
+use tokio::sync::Notify;
+use tokio_serial::{DataBits, FlowControl, Parity, StopBits};
 use url::Url;
 
+mod metrics;
 mod v1;
 
 use crate::libs::{
+    ack_tracker::{self, AckTracker},
     config::{self, Config},
-    data_handler::MgrHandler,
+    crypto::NonceCounters,
+    data_handler::{MgrHandler, Options as HandlerOptions},
+    lora_sim::{SimOptions, SimTxData},
     lora_task::{LoraTask, Options as TaskOptions},
-    DlData, UlData,
+    lora_usb::{self, IfroglabLoraOptions, UsbFilter},
+    metrics::Metrics,
+    queue_file,
+    result_buffer::ResultBuffer,
+    ul_decoder::UlDecoders,
+    DlData, NodeEvent, NodeStatus, TokenBucket, UlData,
 };
 
 /// The resources used by this service.
@@ -31,9 +55,41 @@ pub struct State {
     pub latest_uldata: Arc<Mutex<VecDeque<UlData>>>,
     pub latest_dldata: Arc<Mutex<VecDeque<DlData>>>,
     pub queue_dldata: Arc<Mutex<HashMap<String, VecDeque<DlData>>>>,
+    /// TX budgets of nodes with `node_tx_per_minute`.
+    pub tx_budget: Arc<Mutex<HashMap<String, TokenBucket>>>,
+    /// Devices added by the broker.
+    pub devices: Arc<Mutex<HashSet<String>>>,
+    /// The status of each address heard by LoRa tasks.
+    pub nodes: Arc<Mutex<HashMap<String, NodeStatus>>>,
+    /// Offline and online events of nodes.
+    pub events: Arc<Mutex<VecDeque<NodeEvent>>>,
+    /// Downlink data results that cannot be sent to the broker.
+    pub result_buffer: Arc<ResultBuffer>,
+    /// Metrics of the broker data handler.
+    pub metrics: Arc<Metrics>,
+    pub dongles: Vec<Dongle>,
+    /// The connected device path of each dongle index.
+    pub port_paths: Arc<Mutex<HashMap<usize, String>>>,
+    /// Uplink data buffered while the message queue is not ready.
+    pub offline_uldata: Arc<Mutex<VecDeque<NetUlData>>>,
+    pub max_queue_per_addr: usize,
+    pub max_dl_payload_bytes: usize,
+    /// Maximum downlink data to be transmitted to one node per minute. `None` means unlimited.
+    pub node_tx_per_minute: Option<u32>,
+    /// The bearer token required by APIs except `GET`.
+    pub api_token: Option<String>,
+    /// Transmitted frames of simulated dongles. `None` means using USB dongles.
+    pub sim_tx: Option<Arc<Mutex<VecDeque<SimTxData>>>>,
+    lora_tasks: Vec<LoraTask>, // use private to run in background
+}
+
+/// The USB dongle served by one LoRa task.
+#[derive(Clone)]
+pub struct Dongle {
+    pub dev_path: String,
     pub freq: u32,
     pub power: u8,
-    _lora_task: LoraTask, // use private to run in background
+    pub metrics: Arc<Metrics>,
 }
 
 /// To create resources for the service.
@@ -42,14 +98,100 @@ pub async fn new_state(
     conf: &Config,
 ) -> Result<State, Box<dyn StdError>> {
     let conf = config::apply_default(conf);
+    if let Err(errs) = config::validate(&conf) {
+        return Err(Box::new(IoError::new(
+            ErrorKind::InvalidInput,
+            errs.join(", "),
+        )));
+    }
+    let sim = match conf.simulate.unwrap() {
+        false => None,
+        true => Some(SimOptions {
+            node_ids: conf
+                .sim_node_ids
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter_map(|id| u32::from_str_radix(id, 16).ok())
+                .collect(),
+            interval_ms: conf.sim_interval_ms.unwrap(),
+            tx: Arc::new(Mutex::new(VecDeque::new())),
+        }),
+    };
+    let decoders = UlDecoders::new(&conf.decoders.clone().unwrap_or_default())?;
+    let ack_tracker = match conf.ack_mode.as_ref().unwrap().as_str() {
+        ack_tracker::ACK_MODE_NONE => None,
+        ack_tracker::ACK_MODE_ECHO4 => Some(Arc::new(Mutex::new(AckTracker::new(
+            conf.ack_timeout_secs.unwrap(),
+            conf.ack_retries.unwrap(),
+        )))),
+        mode => {
+            let e = format!("invalid ackMode {}", mode);
+            return Err(Box::new(IoError::new(ErrorKind::InvalidInput, e)));
+        }
+    };
+    // Dongles with fixed paths must be plugged before starting. Use `auto` to detect dongles that
+    // are plugged later.
+    for dongle in conf.dongles.as_ref().unwrap().iter() {
+        let path = dongle.dev_path.as_ref().unwrap();
+        if sim.is_none()
+            && !cfg!(windows)
+            && path.as_str() != lora_usb::DEV_PATH_AUTO
+            && !Path::new(path).exists()
+        {
+            let e = format!("devPath {} does not exist", path);
+            return Err(Box::new(IoError::new(ErrorKind::NotFound, e)));
+        }
+    }
     let host_uri = match Url::parse(conf.mq_uri.as_ref().unwrap()) {
         Err(e) => return Err(Box::new(IoError::new(ErrorKind::InvalidInput, e))),
         Ok(uri) => uri,
     };
 
     let mq_conns = Arc::new(Mutex::new(HashMap::new()));
-    let queue_dldata = Arc::new(Mutex::new(HashMap::new()));
-    let handler = Arc::new(MgrHandler::new(queue_dldata.clone()));
+    let queue_dldata = match conf.queue_file.as_ref() {
+        None => Arc::new(Mutex::new(HashMap::new())),
+        Some(path) => Arc::new(Mutex::new(queue_file::load(path.as_str()))),
+    };
+    let metrics = Arc::new(Metrics::default());
+    let devices = Arc::new(Mutex::new(HashSet::new()));
+    let nodes = Arc::new(Mutex::new(HashMap::new()));
+    let events = Arc::new(Mutex::new(VecDeque::new()));
+    let result_buffer = Arc::new(ResultBuffer::new(conf.result_buffer_size.unwrap()));
+    let tx_budget = Arc::new(Mutex::new(HashMap::new()));
+    let dl_nonces = Arc::new(NonceCounters::new(conf.nonce_file.clone())?);
+    let offline_uldata = Arc::new(Mutex::new(VecDeque::new()));
+    let dongle_confs = conf.dongles.as_ref().unwrap();
+    let immediate = match conf.tx_mode.as_ref().unwrap().as_str() {
+        config::TX_MODE_AFTER_RX => false,
+        config::TX_MODE_IMMEDIATE => true,
+        mode => {
+            let e = format!("invalid txMode {}", mode);
+            return Err(Box::new(IoError::new(ErrorKind::InvalidInput, e)));
+        }
+    };
+    let tx_notify: Vec<Option<Arc<Notify>>> = dongle_confs
+        .iter()
+        .map(|_| match immediate {
+            false => None,
+            true => Some(Arc::new(Notify::new())),
+        })
+        .collect();
+    let handler = Arc::new(MgrHandler::new(HandlerOptions {
+        queue_dldata: queue_dldata.clone(),
+        devices: devices.clone(),
+        nodes: nodes.clone(),
+        offline_uldata: offline_uldata.clone(),
+        metrics: metrics.clone(),
+        max_queue_per_addr: conf.max_queue_per_addr.unwrap(),
+        max_dl_payload_bytes: conf.max_dl_payload_bytes.unwrap(),
+        whitelist_only: conf.whitelist_only.unwrap(),
+        reject_unknown_addr: conf.reject_unknown_addr.unwrap(),
+        unknown_addr_grace_secs: conf.unknown_addr_grace_secs.unwrap(),
+        tx_notify: tx_notify.iter().flatten().cloned().collect(),
+        replace_latest: conf.queue_mode.as_deref() == Some(config::QUEUE_MODE_REPLACE_LATEST),
+        result_buffer: result_buffer.clone(),
+    }));
     let opts = MgrOptions {
         unit_id: conf.unit.as_ref().unwrap().clone(),
         unit_code: conf.unit.as_ref().unwrap().clone(),
@@ -64,15 +206,83 @@ pub async fn new_state(
 
     let latest_uldata = Arc::new(Mutex::new(VecDeque::new()));
     let latest_dldata = Arc::new(Mutex::new(VecDeque::new()));
-    let opts = TaskOptions {
-        mgr: mgr.clone(),
-        latest_uldata: latest_uldata.clone(),
-        latest_dldata: latest_dldata.clone(),
-        queue_dldata: queue_dldata.clone(),
-        dev_path: conf.dev_path.unwrap(),
-        freq: conf.freq.unwrap(),
-        power: conf.power.unwrap(),
+    let addr_dongles = Arc::new(Mutex::new(HashMap::new()));
+    let mut dongles = vec![];
+    let port_opts = port_options(&conf)?;
+    let usb_filter = UsbFilter {
+        vid: parse_usb_id("usbVid", conf.usb_vid.as_ref())?,
+        pid: parse_usb_id("usbPid", conf.usb_pid.as_ref())?,
     };
+    // Fixed device paths are reserved so that they will not be detected by other dongles.
+    let port_paths: HashMap<usize, String> = dongle_confs
+        .iter()
+        .enumerate()
+        .map(|(index, dongle)| (index, dongle.dev_path.as_ref().unwrap().clone()))
+        .filter(|(_, path)| path.as_str() != lora_usb::DEV_PATH_AUTO)
+        .collect();
+    let port_paths = Arc::new(Mutex::new(port_paths));
+    let mut lora_tasks = vec![];
+    for (index, (dongle_conf, tx_notify)) in dongle_confs.iter().zip(tx_notify).enumerate() {
+        let dongle = Dongle {
+            dev_path: dongle_conf.dev_path.as_ref().unwrap().clone(),
+            freq: dongle_conf.freq.unwrap(),
+            power: dongle_conf.power.unwrap(),
+            metrics: Arc::new(Metrics::default()),
+        };
+        let opts = TaskOptions {
+            index,
+            addr_dongles: addr_dongles.clone(),
+            port_paths: port_paths.clone(),
+            mgr: mgr.clone(),
+            latest_uldata: latest_uldata.clone(),
+            latest_dldata: latest_dldata.clone(),
+            queue_dldata: queue_dldata.clone(),
+            tx_budget: tx_budget.clone(),
+            dl_nonces: dl_nonces.clone(),
+            devices: devices.clone(),
+            nodes: nodes.clone(),
+            events: events.clone(),
+            offline_uldata: offline_uldata.clone(),
+            ack_tracker: ack_tracker.clone(),
+            result_buffer: result_buffer.clone(),
+            metrics: dongle.metrics.clone(),
+            dev_path: dongle.dev_path.clone(),
+            freq: dongle.freq,
+            freq_list: conf.freq_list.clone().unwrap_or_default(),
+            power: dongle.power,
+            bw: dongle_conf.bw.unwrap(),
+            cr: dongle_conf.cr.unwrap(),
+            sf: dongle_conf.sf.unwrap(),
+            poll_interval_ms: conf.poll_interval_ms.unwrap(),
+            dldata_ttl_secs: conf.dldata_ttl_secs.unwrap(),
+            tx_retries: conf.tx_retries.unwrap(),
+            max_dl_payload_bytes: conf.max_dl_payload_bytes.unwrap(),
+            offline_buffer_size: conf.offline_buffer_size.unwrap(),
+            max_tx_per_window: conf.max_tx_per_window.unwrap(),
+            reconnect_failures: conf.reconnect_failures.unwrap(),
+            reset_failures: conf.reset_failures.unwrap(),
+            health_check_secs: conf.health_check_secs.unwrap(),
+            port_opts,
+            usb_filter,
+            min_rssi: conf.min_rssi,
+            dedup_ms: conf.dedup_ms.unwrap(),
+            align_ms: conf.align_ms,
+            node_tx_per_minute: conf.node_tx_per_minute,
+            max_nodes: conf.max_nodes.unwrap(),
+            node_offline_secs: conf.node_offline_secs,
+            publish_node_events: conf.publish_node_events.unwrap(),
+            keys: conf.keys.clone().unwrap_or_default(),
+            decoders: decoders.clone(),
+            whitelist_only: conf.whitelist_only.unwrap(),
+            queue_file: conf.queue_file.clone(),
+            cancel_on_shutdown: conf.cancel_on_shutdown.unwrap(),
+            fair_tx: conf.fair_tx.unwrap(),
+            sim: sim.clone(),
+            tx_notify,
+        };
+        lora_tasks.push(LoraTask::new(opts)?);
+        dongles.push(dongle);
+    }
 
     Ok(State {
         scope_path,
@@ -81,16 +291,149 @@ pub async fn new_state(
         latest_uldata,
         latest_dldata,
         queue_dldata,
-        freq: conf.freq.unwrap(),
-        power: conf.power.unwrap(),
-        _lora_task: LoraTask::new(opts)?,
+        tx_budget,
+        devices,
+        nodes,
+        events,
+        result_buffer,
+        metrics,
+        dongles,
+        port_paths,
+        offline_uldata,
+        max_queue_per_addr: conf.max_queue_per_addr.unwrap(),
+        max_dl_payload_bytes: conf.max_dl_payload_bytes.unwrap(),
+        node_tx_per_minute: conf.node_tx_per_minute,
+        api_token: conf.api_token.clone(),
+        sim_tx: sim.map(|sim| sim.tx),
+        lora_tasks,
+    })
+}
+
+/// To parse the hexadecimal USB VID/PID.
+fn parse_usb_id(name: &str, value: Option<&String>) -> Result<Option<u16>, IoError> {
+    match value {
+        None => Ok(None),
+        Some(value) => match u16::from_str_radix(value.as_str(), 16) {
+            Err(_) => {
+                let e = format!("invalid {} {}", name, value);
+                Err(IoError::new(ErrorKind::InvalidInput, e))
+            }
+            Ok(id) => Ok(Some(id)),
+        },
+    }
+}
+
+/// To build the serial port options of dongles. Invalid values will be reported at startup.
+fn port_options(conf: &Config) -> Result<IfroglabLoraOptions, IoError> {
+    let baud_rate = conf.serial_baud.unwrap();
+    if !lora_usb::SUPPORTED_BAUD_RATES.contains(&baud_rate) {
+        let e = format!(
+            "unsupported serialBaud {}, should be one of {:?}",
+            baud_rate,
+            lora_usb::SUPPORTED_BAUD_RATES
+        );
+        return Err(IoError::new(ErrorKind::InvalidInput, e));
+    }
+    let data_bits = match conf.serial_data_bits.unwrap() {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        8 => DataBits::Eight,
+        bits => {
+            let e = format!("invalid serialDataBits {}", bits);
+            return Err(IoError::new(ErrorKind::InvalidInput, e));
+        }
+    };
+    let parity = match conf.serial_parity.as_ref().unwrap().as_str() {
+        config::SERIAL_PARITY_NONE => Parity::None,
+        config::SERIAL_PARITY_ODD => Parity::Odd,
+        config::SERIAL_PARITY_EVEN => Parity::Even,
+        parity => {
+            let e = format!("invalid serialParity {}", parity);
+            return Err(IoError::new(ErrorKind::InvalidInput, e));
+        }
+    };
+    let stop_bits = match conf.serial_stop_bits.unwrap() {
+        1 => StopBits::One,
+        2 => StopBits::Two,
+        bits => {
+            let e = format!("invalid serialStopBits {}", bits);
+            return Err(IoError::new(ErrorKind::InvalidInput, e));
+        }
+    };
+    let flow_control = match conf.serial_flow_control.as_ref().unwrap().as_str() {
+        config::SERIAL_FLOW_CONTROL_NONE => FlowControl::None,
+        config::SERIAL_FLOW_CONTROL_SOFTWARE => FlowControl::Software,
+        config::SERIAL_FLOW_CONTROL_HARDWARE => FlowControl::Hardware,
+        flow_control => {
+            let e = format!("invalid serialFlowControl {}", flow_control);
+            return Err(IoError::new(ErrorKind::InvalidInput, e));
+        }
+    };
+    if flow_control == FlowControl::Hardware && conf.serial_rts.is_some() {
+        let e = "serialRts cannot be set with the hardware serialFlowControl";
+        return Err(IoError::new(ErrorKind::InvalidInput, e));
+    }
+    Ok(IfroglabLoraOptions {
+        ack_sleep_ms: conf.ack_sleep_ms.unwrap(),
+        timeout_ms: conf.ack_timeout_ms.unwrap(),
+        max_retries: conf.ack_max_retries,
+        baud_rate,
+        data_bits,
+        parity,
+        stop_bits,
+        serial_timeout_ms: conf.serial_timeout_ms.unwrap(),
+        flow_control,
+        dtr: conf.serial_dtr,
+        rts: conf.serial_rts,
     })
 }
 
+impl State {
+    /// To stop all LoRa tasks. The task of the first dongle is stopped last to report or save
+    /// queuing downlink data after all dongles stop transmitting.
+    pub async fn shutdown(&self) {
+        for task in self.lora_tasks.iter().rev() {
+            task.shutdown().await;
+        }
+    }
+}
+
 /// To register service URIs in the specified root path.
 pub fn new_service(state: &State) -> Router {
     Router::new().nest(
         state.scope_path,
-        Router::new().merge(v1::data::new_service("/api/v1/data", state)),
+        Router::new()
+            .merge(v1::data::new_service("/api/v1/data", state))
+            .merge(v1::device::new_service("/api/v1/devices", state))
+            .merge(v1::node::new_service("/api/v1/nodes", state))
+            .merge(v1::event::new_service("/api/v1/events", state))
+            .merge(v1::status::new_service("/api/v1/status", state))
+            .merge(v1::stats::new_service("/api/v1/stats", state))
+            .merge(metrics::new_service("/metrics", state))
+            .layer(middleware::from_fn_with_state(state.clone(), auth)),
     )
 }
+
+/// To check the `Authorization: Bearer <token>` header of requests except `GET` when `api_token`
+/// is configured.
+async fn auth(extract::State(state): extract::State<State>, req: Request, next: Next) -> Response {
+    let token = match state.api_token.as_ref() {
+        None => return next.run(req).await,
+        Some(token) => token,
+    };
+    if req.method() == Method::GET || req.method() == Method::HEAD {
+        return next.run(req).await;
+    }
+    let authorized = match req.headers().get(header::AUTHORIZATION) {
+        None => false,
+        Some(value) => match value.to_str() {
+            Err(_) => false,
+            Ok(value) => value.strip_prefix("Bearer ") == Some(token.as_str()),
+        },
+    };
+    match authorized {
+        false => ErrResp::ErrAuth(Some("invalid token".to_string())).into_response(),
+        true => next.run(req).await,
+    }
+}
@@ -1,15 +1,46 @@
+use std::collections::BTreeMap;
+
 use axum::{extract::State, response::IntoResponse, routing, Router};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sylvia_iot_sdk::util::http::{Json, Path};
+use sylvia_iot_sdk::util::{
+    err::ErrResp,
+    http::{Json, Path, Query},
+    strings,
+};
 
 use super::super::State as AppState;
 use crate::libs::{DlData, UlData};
 
+/// The maximum RSSI bucket size in seconds (one year).
+const MAX_RSSI_BUCKET_SECS: u64 = 86400 * 365;
+
 #[derive(Deserialize)]
 struct GetQueueParam {
     network_addr: String,
 }
 
+#[derive(Deserialize)]
+struct GetDlDataQuery {
+    /// Start time in RFC 3339 format.
+    start: Option<String>,
+    /// End time in RFC 3339 format.
+    end: Option<String>,
+    /// The result status of the data.
+    result: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct GetRssiParam {
+    network_addr: String,
+}
+
+#[derive(Deserialize)]
+struct GetRssiQuery {
+    /// Bucket size in seconds to average RSSI values.
+    bucket: Option<u64>,
+}
+
 #[derive(Serialize)]
 struct GetUlDataRes {
     data: Vec<UlData>,
@@ -25,13 +56,52 @@ struct GetQueueRes {
     data: Vec<DlData>,
 }
 
+#[derive(Serialize)]
+struct GetQueuesRes {
+    data: GetQueuesData,
+}
+
+#[derive(Serialize)]
+struct GetQueuesData {
+    #[serde(rename = "maxQueuePerAddr")]
+    max_queue_per_addr: usize,
+    #[serde(rename = "maxDlPayloadBytes")]
+    max_dl_payload_bytes: usize,
+    #[serde(rename = "nodeTxPerMinute")]
+    node_tx_per_minute: Option<u32>,
+    queues: Vec<QueueDepth>,
+}
+
+#[derive(Serialize)]
+struct QueueDepth {
+    #[serde(rename = "networkAddr")]
+    network_addr: String,
+    count: usize,
+    /// Available TX tokens of the node if `nodeTxPerMinute` is specified.
+    #[serde(rename = "txBudget", skip_serializing_if = "Option::is_none")]
+    tx_budget: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct GetRssiRes {
+    data: Vec<RssiData>,
+}
+
+#[derive(Serialize)]
+struct RssiData {
+    time: String,
+    rssi: i16,
+}
+
 pub fn new_service(scope_path: &str, state: &AppState) -> Router {
     Router::new().nest(
         scope_path,
         Router::new()
             .route("/uldata", routing::get(get_uldata))
             .route("/dldata", routing::get(get_dldata))
+            .route("/queue", routing::get(get_queues))
             .route("/queue/{network_addr}", routing::get(get_queue))
+            .route("/rssi/{network_addr}", routing::get(get_rssi))
             .with_state(state.clone()),
     )
 }
@@ -46,12 +116,96 @@ async fn get_uldata(State(state): State<AppState>) -> impl IntoResponse {
 }
 
 /// `GET /{base}/api/v1/data/dldata`
-async fn get_dldata(State(state): State<AppState>) -> impl IntoResponse {
+///
+/// The `start` and `end` query parameters filter data by the `sent` time (or the `time` if the
+/// data has not been sent).
+async fn get_dldata(
+    State(state): State<AppState>,
+    Query(query): Query<GetDlDataQuery>,
+) -> impl IntoResponse {
+    let start = match query.start.as_ref() {
+        None => None,
+        Some(start) => match parse_query_time("start", start.as_str()) {
+            Err(e) => return Err(e),
+            Ok(start) => Some(start),
+        },
+    };
+    let end = match query.end.as_ref() {
+        None => None,
+        Some(end) => match parse_query_time("end", end.as_str()) {
+            Err(e) => return Err(e),
+            Ok(end) => Some(end),
+        },
+    };
+
     let data: Vec<DlData> = {
         let mutex = state.latest_dldata.lock().unwrap();
-        (*mutex).iter().map(|x| x.clone()).collect()
+        if start.is_none() && end.is_none() && query.result.is_none() {
+            (*mutex).iter().map(|x| x.clone()).collect()
+        } else {
+            (*mutex)
+                .iter()
+                .filter(|x| {
+                    if query.result.is_some() && x.result != query.result {
+                        return false;
+                    }
+                    let time = match x.sent.len() {
+                        0 => x.time.as_str(),
+                        _ => x.sent.as_str(),
+                    };
+                    let time = match DateTime::parse_from_rfc3339(time) {
+                        Err(_) => return false,
+                        Ok(time) => time.with_timezone(&Utc),
+                    };
+                    if let Some(start) = start.as_ref() {
+                        if time < *start {
+                            return false;
+                        }
+                    }
+                    if let Some(end) = end.as_ref() {
+                        if time > *end {
+                            return false;
+                        }
+                    }
+                    true
+                })
+                .map(|x| x.clone())
+                .collect()
+        }
+    };
+    Ok(Json(GetDlDataRes { data }))
+}
+
+/// `GET /{base}/api/v1/data/queue`
+async fn get_queues(State(state): State<AppState>) -> impl IntoResponse {
+    let now_ms = Utc::now().timestamp_millis();
+    let mut queues: Vec<QueueDepth> = {
+        let mutex = state.queue_dldata.lock().unwrap();
+        let tx_budget = state.tx_budget.lock().unwrap();
+        (*mutex)
+            .iter()
+            .map(|(addr, queue)| QueueDepth {
+                network_addr: addr.clone(),
+                count: queue.len(),
+                tx_budget: match state.node_tx_per_minute {
+                    None => None,
+                    Some(per_minute) => match (*tx_budget).get(addr.as_str()) {
+                        None => Some(per_minute),
+                        Some(bucket) => Some(bucket.available(per_minute, now_ms) as u32),
+                    },
+                },
+            })
+            .collect()
     };
-    Json(GetDlDataRes { data })
+    queues.sort_by(|a, b| a.network_addr.cmp(&b.network_addr));
+    Json(GetQueuesRes {
+        data: GetQueuesData {
+            max_queue_per_addr: state.max_queue_per_addr,
+            max_dl_payload_bytes: state.max_dl_payload_bytes,
+            node_tx_per_minute: state.node_tx_per_minute,
+            queues,
+        },
+    })
 }
 
 /// `GET /{base}/api/v1/data/queue/{network_addr}`
@@ -61,10 +215,87 @@ async fn get_queue(
 ) -> impl IntoResponse {
     let data: Vec<DlData> = {
         let mutex = state.queue_dldata.lock().unwrap();
-        match (*mutex).get(param.network_addr.as_str()) {
+        match (*mutex).get(param.network_addr.to_lowercase().as_str()) {
             None => vec![],
             Some(data) => data.iter().map(|x| x.clone()).collect(),
         }
     };
     Json(GetQueueRes { data })
 }
+
+/// `GET /{base}/api/v1/data/rssi/{network_addr}`
+async fn get_rssi(
+    State(state): State<AppState>,
+    Path(param): Path<GetRssiParam>,
+    Query(query): Query<GetRssiQuery>,
+) -> impl IntoResponse {
+    match query.bucket {
+        Some(0) => {
+            return Err(ErrResp::ErrParam(Some(
+                "`bucket` must be positive".to_string(),
+            )));
+        }
+        Some(bucket) if bucket > MAX_RSSI_BUCKET_SECS => {
+            return Err(ErrResp::ErrParam(Some(format!(
+                "`bucket` must not exceed {}",
+                MAX_RSSI_BUCKET_SECS
+            ))));
+        }
+        _ => (),
+    }
+
+    let addr = param.network_addr.to_lowercase();
+    let data: Vec<(DateTime<Utc>, i16)> = {
+        let mutex = state.latest_uldata.lock().unwrap();
+        (*mutex)
+            .iter()
+            .filter(|x| x.network_addr.eq(addr.as_str()))
+            .filter_map(|x| match DateTime::parse_from_rfc3339(x.time.as_str()) {
+                Err(_) => None,
+                Ok(time) => Some((time.with_timezone(&Utc), x.extension.rssi)),
+            })
+            .collect()
+    };
+    let data = match query.bucket {
+        None => data
+            .iter()
+            .map(|(time, rssi)| RssiData {
+                time: strings::time_str(time),
+                rssi: *rssi,
+            })
+            .collect(),
+        Some(bucket) => bucket_rssi(&data, bucket as i64),
+    };
+    Ok(Json(GetRssiRes { data }))
+}
+
+/// To average RSSI values in buckets of `bucket` seconds. The time of each result is the start
+/// time of the bucket.
+fn bucket_rssi(data: &[(DateTime<Utc>, i16)], bucket: i64) -> Vec<RssiData> {
+    let mut buckets: BTreeMap<i64, (i64, i64)> = BTreeMap::new();
+    for (time, rssi) in data {
+        let start = time.timestamp().div_euclid(bucket) * bucket;
+        let entry = buckets.entry(start).or_insert((0, 0));
+        entry.0 += *rssi as i64;
+        entry.1 += 1;
+    }
+    buckets
+        .iter()
+        .filter_map(|(start, (sum, count))| {
+            DateTime::from_timestamp(*start, 0).map(|time| RssiData {
+                time: strings::time_str(&time),
+                rssi: (sum / count) as i16,
+            })
+        })
+        .collect()
+}
+
+fn parse_query_time(name: &str, value: &str) -> Result<DateTime<Utc>, ErrResp> {
+    match DateTime::parse_from_rfc3339(value) {
+        Err(e) => Err(ErrResp::ErrParam(Some(format!(
+            "`{}` is not RFC 3339 format: {}",
+            name, e
+        )))),
+        Ok(time) => Ok(time.with_timezone(&Utc)),
+    }
+}
@@ -0,0 +1,66 @@
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use serde::Serialize;
+use sylvia_iot_sdk::util::{err::ErrResp, http::Json, strings};
+
+use super::super::State as AppState;
+use crate::libs::lora_sim::SimTxData;
+
+#[derive(Serialize)]
+struct GetDevicesRes {
+    data: Vec<Device>,
+}
+
+#[derive(Serialize)]
+struct GetSimTxRes {
+    data: Vec<SimTxData>,
+}
+
+#[derive(Serialize)]
+struct Device {
+    #[serde(rename = "networkAddr")]
+    network_addr: String,
+    #[serde(rename = "lastSeen")]
+    last_seen: Option<String>,
+}
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(scope_path, routing::get(get_devices))
+        .route(&format!("{}/sim/tx", scope_path), routing::get(get_sim_tx))
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/api/v1/devices`
+async fn get_devices(State(state): State<AppState>) -> impl IntoResponse {
+    let mut data: Vec<Device> = {
+        let mutex = state.devices.lock().unwrap();
+        let nodes = state.nodes.lock().unwrap();
+        (*mutex)
+            .iter()
+            .map(|addr| Device {
+                network_addr: addr.clone(),
+                last_seen: (*nodes)
+                    .get(addr)
+                    .map(|node| strings::time_str(&node.last_seen)),
+            })
+            .collect()
+    };
+    data.sort_by(|a, b| a.network_addr.cmp(&b.network_addr));
+    Json(GetDevicesRes { data })
+}
+
+/// `GET /{base}/api/v1/devices/sim/tx`
+async fn get_sim_tx(State(state): State<AppState>) -> impl IntoResponse {
+    let sim_tx = match state.sim_tx.as_ref() {
+        None => {
+            let e = "simulated dongles are not enabled".to_string();
+            return Err(ErrResp::ErrNotFound(Some(e)));
+        }
+        Some(sim_tx) => sim_tx,
+    };
+    let data = {
+        let mutex = sim_tx.lock().unwrap();
+        (*mutex).iter().cloned().collect()
+    };
+    Ok(Json(GetSimTxRes { data }))
+}
@@ -0,0 +1,26 @@
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use serde::Serialize;
+use sylvia_iot_sdk::util::http::Json;
+
+use super::super::State as AppState;
+use crate::libs::NodeEvent;
+
+#[derive(Serialize)]
+struct GetEventsRes {
+    data: Vec<NodeEvent>,
+}
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(scope_path, routing::get(get_events))
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/api/v1/events`
+async fn get_events(State(state): State<AppState>) -> impl IntoResponse {
+    let data = {
+        let mutex = state.events.lock().unwrap();
+        (*mutex).iter().cloned().collect()
+    };
+    Json(GetEventsRes { data })
+}
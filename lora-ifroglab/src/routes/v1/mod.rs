@@ -1 +1,6 @@
 pub mod data;
+pub mod device;
+pub mod event;
+pub mod node;
+pub mod stats;
+pub mod status;
@@ -0,0 +1,90 @@
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use serde::{Deserialize, Serialize};
+use sylvia_iot_sdk::util::{
+    err::ErrResp,
+    http::{Json, Path},
+    strings,
+};
+
+use super::super::State as AppState;
+use crate::libs::NodeStatus;
+
+#[derive(Deserialize)]
+struct GetNodeParam {
+    network_addr: String,
+}
+
+#[derive(Serialize)]
+struct GetNodesRes {
+    data: Vec<Node>,
+}
+
+#[derive(Serialize)]
+struct GetNodeRes {
+    data: Node,
+}
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(rename = "networkAddr")]
+    network_addr: String,
+    #[serde(rename = "lastSeen")]
+    last_seen: String,
+    #[serde(rename = "lastRssi")]
+    last_rssi: i16,
+    #[serde(rename = "uplinkCount")]
+    uplink_count: u64,
+    offline: bool,
+}
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(scope_path, routing::get(get_nodes))
+        .route(
+            &format!("{}/{{network_addr}}", scope_path),
+            routing::get(get_node),
+        )
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/api/v1/nodes`
+async fn get_nodes(State(state): State<AppState>) -> impl IntoResponse {
+    let mut data: Vec<Node> = {
+        let mutex = state.nodes.lock().unwrap();
+        (*mutex)
+            .iter()
+            .map(|(addr, node)| to_node(addr, node))
+            .collect()
+    };
+    data.sort_by(|a, b| a.network_addr.cmp(&b.network_addr));
+    Json(GetNodesRes { data })
+}
+
+/// `GET /{base}/api/v1/nodes/{network_addr}`
+async fn get_node(
+    State(state): State<AppState>,
+    Path(param): Path<GetNodeParam>,
+) -> impl IntoResponse {
+    let addr = param.network_addr.to_lowercase();
+    let data = {
+        let mutex = state.nodes.lock().unwrap();
+        (*mutex).get(addr.as_str()).map(|node| to_node(&addr, node))
+    };
+    match data {
+        None => Err(ErrResp::ErrNotFound(Some(format!(
+            "node {} is not heard",
+            addr
+        )))),
+        Some(data) => Ok(Json(GetNodeRes { data })),
+    }
+}
+
+fn to_node(addr: &str, node: &NodeStatus) -> Node {
+    Node {
+        network_addr: addr.to_string(),
+        last_seen: strings::time_str(&node.last_seen),
+        last_rssi: node.last_rssi,
+        uplink_count: node.uplink_count,
+        offline: node.offline,
+    }
+}
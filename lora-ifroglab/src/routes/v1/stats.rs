@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use serde::Serialize;
+use sylvia_iot_sdk::{mq::network::DlDataResult, util::http::Json};
+
+use super::super::State as AppState;
+use crate::libs::metrics::QueuedMsStats;
+
+#[derive(Serialize)]
+struct GetInternalRes {
+    data: Internal,
+}
+
+#[derive(Serialize)]
+struct Internal {
+    handler: HashMap<String, u64>,
+    dongles: Vec<Dongle>,
+    /// Buffered downlink data results in order.
+    #[serde(rename = "pendingResults")]
+    pending_results: Vec<DlDataResult>,
+}
+
+#[derive(Serialize)]
+struct Dongle {
+    #[serde(rename = "devPath")]
+    dev_path: String,
+    counters: HashMap<String, u64>,
+    #[serde(rename = "queuedMs")]
+    queued_ms: Option<QueuedMsStats>,
+}
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(
+            &format!("{}/internal", scope_path),
+            routing::get(get_internal),
+        )
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/api/v1/stats/internal`
+async fn get_internal(State(state): State<AppState>) -> impl IntoResponse {
+    let dongles = state
+        .dongles
+        .iter()
+        .map(|dongle| Dongle {
+            dev_path: dongle.dev_path.clone(),
+            counters: dongle.metrics.counters().into_iter().collect(),
+            queued_ms: dongle.metrics.queued_ms_stats(),
+        })
+        .collect();
+    Json(GetInternalRes {
+        data: Internal {
+            handler: state.metrics.counters().into_iter().collect(),
+            dongles,
+            pending_results: state.result_buffer.pending(),
+        },
+    })
+}
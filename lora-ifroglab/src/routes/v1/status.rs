@@ -0,0 +1,157 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use axum::{extract::State, response::IntoResponse, routing, Router};
+use serde::Serialize;
+use sylvia_iot_sdk::{
+    mq::MgrStatus,
+    util::{http::Json, strings},
+};
+
+use super::super::State as AppState;
+
+#[derive(Serialize)]
+struct GetStatusRes {
+    data: Status,
+}
+
+#[derive(Serialize)]
+struct Status {
+    mq: Mq,
+    dongles: Vec<Dongle>,
+}
+
+#[derive(Serialize)]
+struct Mq {
+    /// **ready** or **notReady**.
+    status: &'static str,
+    #[serde(rename = "disconnectCount")]
+    disconnect_count: u64,
+    #[serde(rename = "offlineBuffered")]
+    offline_buffered: usize,
+}
+
+#[derive(Serialize)]
+struct Dongle {
+    #[serde(rename = "devPath")]
+    dev_path: String,
+    #[serde(rename = "portPath")]
+    port_path: Option<String>,
+    freq: u32,
+    power: u8,
+    #[serde(rename = "reconnectCount")]
+    reconnect_count: u64,
+    #[serde(rename = "resetCount")]
+    reset_count: u64,
+    #[serde(rename = "healthCheck")]
+    health_check: Option<HealthCheck>,
+}
+
+#[derive(Serialize)]
+struct HealthCheck {
+    time: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GetValuesRes {
+    data: Vec<DongleValues>,
+}
+
+#[derive(Serialize)]
+struct DongleValues {
+    #[serde(rename = "devPath")]
+    dev_path: String,
+    values: Option<Values>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Values {
+    mode: u8,
+    freq: u32,
+    power: u8,
+    bw: u8,
+    cr: u8,
+    sf: u8,
+}
+
+/// The maximum age of cached chip values to prevent disturbing RX polling by frequent requests.
+const VALUES_MAX_AGE_SECS: u64 = 5;
+
+pub fn new_service(scope_path: &str, state: &AppState) -> Router {
+    Router::new()
+        .route(scope_path, routing::get(get_status))
+        .route(&format!("{}/values", scope_path), routing::get(get_values))
+        .with_state(state.clone())
+}
+
+/// `GET /{base}/api/v1/status`
+async fn get_status(State(state): State<AppState>) -> impl IntoResponse {
+    let mq = Mq {
+        status: match state.mgr.lock().unwrap().status() {
+            MgrStatus::NotReady => "notReady",
+            MgrStatus::Ready => "ready",
+        },
+        disconnect_count: state.metrics.mq_disconnects.load(Ordering::Relaxed),
+        offline_buffered: state.offline_uldata.lock().unwrap().len(),
+    };
+    let port_paths = { state.port_paths.lock().unwrap().clone() };
+    let dongles = state
+        .dongles
+        .iter()
+        .enumerate()
+        .map(|(index, dongle)| Dongle {
+            dev_path: dongle.dev_path.clone(),
+            port_path: port_paths.get(&index).cloned(),
+            freq: dongle.freq,
+            power: dongle.power,
+            reconnect_count: dongle.metrics.reconnects.load(Ordering::Relaxed),
+            reset_count: dongle.metrics.resets.load(Ordering::Relaxed),
+            health_check: dongle
+                .metrics
+                .last_health_check
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|check| HealthCheck {
+                    time: strings::time_str(&check.time),
+                    ok: check.error.is_none(),
+                    error: check.error.clone(),
+                }),
+        })
+        .collect();
+    Json(GetStatusRes {
+        data: Status { mq, dongles },
+    })
+}
+
+/// `GET /{base}/api/v1/status/values`
+async fn get_values(State(state): State<AppState>) -> impl IntoResponse {
+    let max_age = Duration::from_secs(VALUES_MAX_AGE_SECS);
+    let mut data = vec![];
+    for (dongle, task) in state.dongles.iter().zip(state.lora_tasks.iter()) {
+        let (values, error) = match task.chip_values(max_age).await {
+            Err(e) => (None, Some(e.to_string())),
+            Ok(values) => (
+                Some(Values {
+                    mode: values.mode,
+                    freq: values.freq,
+                    power: values.power,
+                    bw: values.bw,
+                    cr: values.cr,
+                    sf: values.sf,
+                }),
+                None,
+            ),
+        };
+        data.push(DongleValues {
+            dev_path: dongle.dev_path.clone(),
+            values,
+            error,
+        });
+    }
+    Json(GetValuesRes { data })
+}